@@ -0,0 +1,318 @@
+use async_openai::{
+    config::{Config, OpenAIConfig},
+    error::{ApiError, OpenAIError},
+};
+use eventsource_stream::Eventsource;
+use futures::{Stream, StreamExt};
+use reqwest::{
+    header::{HeaderMap, HeaderValue},
+    StatusCode,
+};
+use secrecy::Secret;
+use serde_derive::Deserialize;
+use serde_json::Value;
+use std::{pin::Pin, time::Duration};
+
+// the sdk's config plus the headers it doesn't support (eg: OpenAI-Project)
+#[derive(Clone, Debug)]
+pub struct ApiConfig {
+    inner: OpenAIConfig,
+    project_id: Option<String>,
+}
+
+impl ApiConfig {
+    pub fn new(inner: OpenAIConfig, project_id: Option<String>) -> Self {
+        Self { inner, project_id }
+    }
+}
+
+impl Config for ApiConfig {
+    fn headers(&self) -> HeaderMap {
+        let mut headers = self.inner.headers();
+        if let Some(project_id) = &self.project_id {
+            if let Ok(value) = HeaderValue::from_str(project_id) {
+                headers.insert("OpenAI-Project", value);
+            }
+        }
+        headers
+    }
+
+    fn url(&self, path: &str) -> String {
+        self.inner.url(path)
+    }
+
+    fn query(&self) -> Vec<(&str, &str)> {
+        self.inner.query()
+    }
+
+    fn api_base(&self) -> &str {
+        self.inner.api_base()
+    }
+
+    fn api_key(&self) -> &Secret<String> {
+        self.inner.api_key()
+    }
+}
+
+// a streamed chat completion chunk. unlike the sdk's type it keeps the reasoning some models
+// stream next to the content (`reasoning_content` for deepseek, `reasoning` for openrouter)
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct ChatChunk {
+    pub choices: Vec<ChunkChoice>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct ChunkChoice {
+    pub delta: ChunkDelta,
+    pub finish_reason: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct ChunkDelta {
+    pub content: Option<String>,
+    reasoning_content: Option<String>,
+    reasoning: Option<String>,
+}
+
+impl ChunkDelta {
+    pub fn reasoning(&self) -> Option<&str> {
+        self.reasoning_content
+            .as_deref()
+            .or(self.reasoning.as_deref())
+    }
+}
+
+pub type ChatStream = Pin<Box<dyn Stream<Item = Result<ChatChunk, OpenAIError>> + Send>>;
+
+// times a rate limited (429) request is sent again before giving up
+const MAX_RETRIES: u32 = 3;
+
+// what's left of the rate limits, from the x-ratelimit-* headers of a response
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RateLimits {
+    requests: Option<Limit>,
+    tokens: Option<Limit>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Limit {
+    remaining: u64,
+    limit: u64,
+    reset: Option<Duration>, // until it's back to the full limit, from the response
+}
+
+impl RateLimits {
+    fn from_headers(headers: &HeaderMap) -> Self {
+        let header = |name: String| headers.get(name)?.to_str().ok().map(String::from);
+        let limit = |kind: &str| {
+            Some(Limit {
+                remaining: header(format!("x-ratelimit-remaining-{kind}"))?
+                    .parse()
+                    .ok()?,
+                limit: header(format!("x-ratelimit-limit-{kind}"))?.parse().ok()?,
+                reset: header(format!("x-ratelimit-reset-{kind}")).and_then(|r| parse_reset(&r)),
+            })
+        };
+        Self {
+            requests: limit("requests"),
+            tokens: limit("tokens"),
+        }
+    }
+
+    fn kinds(&self) -> [(&'static str, Option<Limit>); 2] {
+        [("requests", self.requests), ("tokens", self.tokens)]
+    }
+
+    // a warning once less than a tenth of the requests or the tokens are left
+    pub fn warning(&self) -> Option<String> {
+        let low: Vec<String> = self
+            .kinds()
+            .into_iter()
+            .filter_map(|(kind, limit)| {
+                let Limit {
+                    remaining, limit, ..
+                } = limit?;
+                (remaining * 10 < limit).then(|| format!("{remaining}/{limit} {kind}"))
+            })
+            .collect();
+        (!low.is_empty()).then(|| format!("rate limits running low: {} left", low.join(", ")))
+    }
+
+    // what's left of each limit and when it resets, None when the api doesn't tell
+    pub fn describe(&self) -> Option<String> {
+        let left: Vec<String> = self
+            .kinds()
+            .into_iter()
+            .filter_map(|(kind, limit)| {
+                let limit = limit?;
+                let reset = match limit.reset {
+                    Some(reset) => format!(", reset in {:.1}s", reset.as_secs_f64()),
+                    None => String::new(),
+                };
+                Some(format!("{}/{} {kind}{reset}", limit.remaining, limit.limit))
+            })
+            .collect();
+        (!left.is_empty()).then(|| format!("rate limits: {} left", left.join(", ")))
+    }
+
+    // how long to wait before a request of ~`tokens` tokens, `elapsed` after the response these
+    // limits come from: until the reset of the ones it would run into
+    pub fn wait(&self, tokens: u64, elapsed: Duration) -> Option<Duration> {
+        self.kinds()
+            .into_iter()
+            .filter_map(|(kind, limit)| {
+                let limit = limit?;
+                let needed = if kind == "tokens" { tokens } else { 1 };
+                (limit.remaining < needed).then_some(limit.reset?)
+            })
+            .max()
+            .and_then(|reset| reset.checked_sub(elapsed))
+    }
+}
+
+// a reset time as the api writes it, eg: `20ms`, `1s`, `6m0s`, `1h2m3.5s`
+fn parse_reset(text: &str) -> Option<Duration> {
+    let mut secs = 0.0;
+    let mut rest = text.trim();
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(rest.len());
+        let value: f64 = rest[..digits].parse().ok()?;
+        rest = &rest[digits..];
+        let (unit, len) = match rest {
+            _ if rest.starts_with("ms") => (0.001, 2),
+            _ if rest.starts_with('h') => (3600.0, 1),
+            _ if rest.starts_with('m') => (60.0, 1),
+            _ if rest.starts_with('s') => (1.0, 1),
+            _ => return None,
+        };
+        secs += value * unit;
+        rest = &rest[len..];
+    }
+    Duration::try_from_secs_f64(secs).ok()
+}
+
+// how long a rate limited response asks to wait before retrying
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let header = |name| headers.get(name)?.to_str().ok()?.parse::<f64>().ok();
+    let secs = header("retry-after-ms")
+        .map(|ms| ms / 1000.0)
+        .or_else(|| header("retry-after"))?;
+    Duration::try_from_secs_f64(secs).ok()
+}
+
+// the message of an error response, the raw body when it's not the usual json
+async fn error_message(response: reqwest::Response) -> String {
+    let status = response.status();
+    let body = response.text().await.unwrap_or_default();
+    let message = serde_json::from_str::<Value>(&body)
+        .ok()
+        .and_then(|json| json["error"]["message"].as_str().map(String::from))
+        .unwrap_or(body);
+    let hint = match status {
+        StatusCode::UNAUTHORIZED => " (check the api key)",
+        StatusCode::NOT_FOUND => " (see --list-models for the models the key has access to)",
+        _ => "",
+    };
+    format!("{status}: {}{hint}", message.trim())
+}
+
+// an error as told to the user, rather than the sdk's debug output
+pub fn describe_error(e: &OpenAIError) -> String {
+    match e {
+        OpenAIError::Reqwest(e) => format!("failed to reach the api: {e}"),
+        OpenAIError::ApiError(e) => match &e.r#type {
+            Some(kind) => format!("{kind}: {}", e.message),
+            None => e.message.clone(),
+        },
+        OpenAIError::JSONDeserialize(e) => format!("unexpected response from the api: {e}"),
+        OpenAIError::StreamError(message) | OpenAIError::InvalidArgument(message) => {
+            message.clone()
+        }
+        e => e.to_string(),
+    }
+}
+
+// a chunk of the stream, or the error the api sent in its place (eg: the output was blocked
+// mid-generation), which would otherwise pass for a chunk without choices
+fn parse_chunk(data: &str) -> Result<ChatChunk, OpenAIError> {
+    let json: Value = serde_json::from_str(data).map_err(OpenAIError::JSONDeserialize)?;
+    if let Some(error) = json.get("error") {
+        return Err(match serde_json::from_value::<ApiError>(error.clone()) {
+            Ok(error) => OpenAIError::ApiError(error),
+            Err(_) => OpenAIError::StreamError(error.to_string()),
+        });
+    }
+    serde_json::from_value(json).map_err(OpenAIError::JSONDeserialize)
+}
+
+// same as `client.chat().create_stream()`, but the request body is plain json so it can carry
+// the fields the sdk doesn't know about yet (eg: reasoning_effort, max_completion_tokens). the
+// rate limited requests are retried after the wait the api asks for. a request the api rejects
+// up front (eg: a bad parameter, a model the key can't use) is an error rather than a stream
+pub async fn create_stream_raw(
+    config: &ApiConfig,
+    http: &reqwest::Client,
+    mut body: Value,
+) -> Result<(ChatStream, RateLimits), OpenAIError> {
+    body["stream"] = Value::Bool(true);
+    let mut retries = 0;
+    let response = loop {
+        let response = http
+            .post(config.url("/chat/completions"))
+            .query(&config.query())
+            .headers(config.headers())
+            .json(&body)
+            .send()
+            .await;
+        match response {
+            Ok(response)
+                if response.status() == StatusCode::TOO_MANY_REQUESTS && retries < MAX_RETRIES =>
+            {
+                retries += 1;
+                let wait =
+                    retry_after(response.headers()).unwrap_or(Duration::from_secs(1 << retries));
+                // may be in raw mode, when resuming a response
+                eprint!(
+                    "rate limited, retrying in {:.1}s ({retries}/{MAX_RETRIES})\r\n",
+                    wait.as_secs_f64()
+                );
+                tokio::time::sleep(wait).await;
+            }
+            Ok(response) if response.status().is_success() => break response,
+            Ok(response) => {
+                return Err(OpenAIError::InvalidArgument(error_message(response).await));
+            }
+            Err(e) => return Err(OpenAIError::Reqwest(e)),
+        }
+    };
+    let limits = RateLimits::from_headers(response.headers());
+
+    let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+    let stream: ChatStream = Box::pin(tokio_stream::wrappers::UnboundedReceiverStream::new(rx));
+
+    tokio::spawn(async move {
+        let mut events = std::pin::pin!(response.bytes_stream().eventsource());
+        while let Some(event) = events.next().await {
+            let response = match event {
+                Ok(event) if event.data == "[DONE]" => return,
+                Ok(event) => parse_chunk(&event.data),
+                Err(e) => {
+                    let _ = tx.send(Err(OpenAIError::StreamError(e.to_string())));
+                    return;
+                }
+            };
+            if tx.send(response).is_err() {
+                return;
+            }
+        }
+        // the connection was closed before the end of the response
+        let _ = tx.send(Err(OpenAIError::StreamError("stream ended".to_string())));
+    });
+
+    Ok((stream, limits))
+}
@@ -0,0 +1,96 @@
+use async_openai::types::ChatCompletionRequestAssistantMessageArgs;
+use futures::StreamExt;
+
+use crate::{
+    api::{self, ChatStream},
+    quote,
+    render::ThinkSplitter,
+    App,
+};
+
+// answer the prompts piped in one after the other, eg:
+//
+//  printf 'What is 2+2?\n---\nAnd 3+3?' | cli-gpt --stdin-prompt-separator
+//
+// the responses are printed as plain text, separated like the prompts were, or as json lines
+// with --json. with --stateless every prompt starts from the initial history
+impl App {
+    pub async fn run_batch(&mut self, prompts: Vec<String>, separator: &str) {
+        let initial = self.history.clone();
+        for (i, pmt) in prompts.into_iter().enumerate() {
+            if self.stateless {
+                self.history = initial.clone();
+            }
+            let resp = match self.send_message(pmt.clone()).await {
+                Ok(stream) => self.collect_resp(stream).await,
+                Err(e) => Err(api::describe_error(&e)),
+            };
+            if self.json {
+                let line = match &resp {
+                    Ok(resp) => serde_json::json!({ "prompt": pmt, "response": resp }),
+                    Err(e) => serde_json::json!({ "prompt": pmt, "error": e }),
+                };
+                println!("{line}");
+                continue;
+            }
+            if i > 0 {
+                print!("{separator}");
+            }
+            if self.echo_prompt {
+                // plain markdown, the output is likely going to a file
+                println!("{}", quote(&pmt));
+            }
+            match resp {
+                Ok(resp) => println!("{}", resp.trim_end()),
+                Err(e) => {
+                    println!();
+                    eprintln!("no response to prompt {}: {e}", i + 1);
+                }
+            }
+        }
+        self.autosave();
+    }
+
+    // the whole content of a response, without the reasoning. it goes to the history like the
+    // interactive ones. a failed or empty response takes its prompt out of the history, so the
+    // next prompt doesn't follow a turn without an answer
+    async fn collect_resp(&mut self, stream: ChatStream) -> Result<String, String> {
+        let answer = match collect(stream).await {
+            Ok(answer) => answer,
+            Err(e) => {
+                self.history.pop();
+                return Err(e);
+            }
+        };
+        if answer.trim().is_empty() {
+            self.history.pop();
+            return Err("the response was empty".to_string());
+        }
+        let resp = ChatCompletionRequestAssistantMessageArgs::default()
+            .content(answer.clone())
+            .build()
+            .unwrap();
+        self.history.push(resp.into());
+        Ok(answer)
+    }
+}
+
+// the whole content of a streamed response, without the reasoning, or why it failed
+pub async fn collect(mut stream: ChatStream) -> Result<String, String> {
+    let mut think = ThinkSplitter::default();
+    let mut answer = String::new();
+    while let Some(result) = stream.next().await {
+        match result {
+            Ok(resp) => {
+                for chat_choice in resp.choices {
+                    if let Some(content) = chat_choice.delta.content {
+                        answer.push_str(&think.push(&content).1);
+                    }
+                }
+            }
+            Err(e) => return Err(api::describe_error(&e)),
+        }
+    }
+    answer.push_str(&think.finish().1);
+    Ok(answer)
+}
@@ -0,0 +1,59 @@
+use std::{
+    io::Write,
+    process::{Command, Stdio},
+};
+
+// the tools writing the system clipboard from their stdin, tried in order. on x11 and wayland
+// a text set with arboard is gone as soon as its `Clipboard` is dropped, these tools keep
+// serving it after they exit
+const COPY_COMMANDS: &[&[&str]] = &[
+    #[cfg(target_os = "macos")]
+    &["pbcopy"],
+    #[cfg(windows)]
+    &["clip"],
+    #[cfg(all(unix, not(target_os = "macos")))]
+    &["wl-copy"],
+    #[cfg(all(unix, not(target_os = "macos")))]
+    &["xclip", "-selection", "clipboard"],
+    #[cfg(all(unix, not(target_os = "macos")))]
+    &["xsel", "--clipboard", "--input"],
+];
+
+// read the text in the system clipboard, empty when there's no text in it
+pub fn read() -> Result<String, String> {
+    match arboard::Clipboard::new().and_then(|mut clipboard| clipboard.get_text()) {
+        Ok(text) => Ok(text),
+        Err(arboard::Error::ContentNotAvailable) => Ok(String::new()),
+        Err(e) => Err(format!("can't read the clipboard: {e}")),
+    }
+}
+
+// put a text in the system clipboard
+pub fn write(text: &str) -> Result<(), String> {
+    for cmd in COPY_COMMANDS {
+        let Ok(mut child) = Command::new(cmd[0])
+            .args(&cmd[1..])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+        else {
+            continue;
+        };
+        let written = child
+            .stdin
+            .take()
+            .is_some_and(|mut stdin| stdin.write_all(text.as_bytes()).is_ok());
+        if child.wait().is_ok_and(|status| status.success()) && written {
+            return Ok(());
+        }
+    }
+    Err(format!(
+        "can't write the clipboard, install one of: {}",
+        COPY_COMMANDS
+            .iter()
+            .map(|cmd| cmd[0])
+            .collect::<Vec<_>>()
+            .join(", ")
+    ))
+}
@@ -0,0 +1,565 @@
+use async_openai::types::{
+    ChatCompletionRequestAssistantMessageArgs, ChatCompletionRequestMessage,
+    ChatCompletionRequestSystemMessageArgs, ChatCompletionRequestUserMessageArgs,
+};
+use std::path::Path;
+
+use crate::{
+    api, batch, clipboard, confirm, message_role, message_text, paths, print_error,
+    session::{self, Session},
+    warn_newer_session, App,
+};
+
+pub enum Handled {
+    NotCommand,
+    Done,
+    Send(String), // the command came up with a prompt to send
+    // a prompt to send in place of the exchange, which is put back when it gets no answer
+    Resend(String, Vec<ChatCompletionRequestMessage>),
+}
+
+impl App {
+    // handle the slash commands
+    pub async fn handle_command(&mut self, pmt: &str) -> Handled {
+        let pmt = pmt.trim();
+        let (cmd, arg) = pmt.split_once(' ').unwrap_or((pmt, ""));
+        match cmd {
+            "/context" => self.print_context(),
+            "/history" => self.print_history(),
+            "/delete" => self.delete_message(arg.trim()),
+            "/clear" => match arg.trim() {
+                "" => self.clear(false),
+                "all" => self.clear(true),
+                _ => eprintln!("usage: /clear [all]"),
+            },
+            "/compact" => self.compact(arg.trim().parse().unwrap_or(2)).await,
+            // the gist of the last response, a new turn
+            "/tldr" => self.tldr().await,
+            "/summarize" if arg.trim() == "last" => self.tldr().await,
+            // the same as /compact, but the whole history unless told how many turns to keep
+            "/summarize" => self.compact(arg.trim().parse().unwrap_or(0)).await,
+            "/persona" => match arg.trim() {
+                "" | "list" => self.print_personas(),
+                name => {
+                    self.switch_persona(name);
+                }
+            },
+            "/model" => match arg.trim() {
+                "" => self.cycle_model(),
+                model => self.switch_model(model),
+            },
+            "/paste" => return self.paste(arg.trim()),
+            "/edit-last" => return self.edit_last(),
+            "/export" => self.export(arg.trim()),
+            "/branch" => match arg.trim() {
+                "" | "list" => self.print_branches(),
+                name => self.branch(name),
+            },
+            "/switch" => self.switch_session(arg.trim()),
+            "/retry" | "/regenerate" => self.retry().await,
+            "/continue" => self.continue_last().await,
+            "/save-all" => self.save_all(arg.trim()),
+            _ => return Handled::NotCommand,
+        }
+        Handled::Done
+    }
+
+    // switch to the favorite model following the current one, the first one when it's not a
+    // favorite
+    fn cycle_model(&mut self) {
+        let next = match self.favorite_models.iter().position(|m| *m == self.model) {
+            Some(i) => (i + 1) % self.favorite_models.len(),
+            None => 0,
+        };
+        let Some(model) = self.favorite_models.get(next).cloned() else {
+            eprintln!("{}", self.skin.inline("*no favorite models configured*"));
+            return;
+        };
+        self.switch_model(&model);
+    }
+
+    // the next requests go to `model`
+    fn switch_model(&mut self, model: &str) {
+        self.model = model.to_string();
+        eprintln!("{}", self.skin.inline(&format!("*model: {model}*")));
+        self.notice_ignored_params();
+    }
+
+    // drop the last exchange and bring its prompt back in the editor, to send it once reworded
+    fn edit_last(&mut self) -> Handled {
+        let Some(last) = self
+            .history
+            .iter()
+            .rposition(|m| matches!(m, ChatCompletionRequestMessage::User(_)))
+        else {
+            eprintln!("{}", self.skin.inline("*no prompt to edit*"));
+            return Handled::Done;
+        };
+        let pmt = self
+            .unwrap_prompt(message_text(&self.history[last]))
+            .to_string();
+        let exchange = self.history.split_off(last);
+        let pmt = self.read_pmt(pmt);
+        // an emptied prompt keeps the exchange as it was
+        if pmt.trim().is_empty() {
+            self.history.extend(exchange);
+            return Handled::Done;
+        }
+        Handled::Resend(pmt, exchange)
+    }
+
+    // ask again for the last response, which is replaced by the new one. with --diff the
+    // changes from the previous response are shown under it
+    async fn retry(&mut self) {
+        let Some(last) = self
+            .history
+            .iter()
+            .rposition(|m| matches!(m, ChatCompletionRequestMessage::User(_)))
+        else {
+            eprintln!("{}", self.skin.inline("*no prompt to retry*"));
+            return;
+        };
+        let previous = self.history.split_off(last + 1);
+        let body = self.request_body(self.window());
+        let stream = self.request(body).await;
+        if !self.respond(stream).await {
+            self.history.extend(previous);
+            return;
+        }
+        if self.diff {
+            let previous: Vec<&str> = previous.iter().map(message_text).collect();
+            let new = self.history.last().map(message_text).unwrap_or_default();
+            eprintln!(
+                "{}",
+                self.skin.inline("*changes from the previous response:*")
+            );
+            self.print_diff(&previous.join("\n"), new);
+        }
+    }
+
+    // write the conversation as a markdown transcript, it can be resumed with --continue-from
+    fn export(&self, path: &str) {
+        if path.is_empty() {
+            eprintln!("usage: /export <file>");
+            return;
+        }
+        match session::export(
+            &self.history,
+            Path::new(path),
+            self.export_system,
+            self.role_tag,
+        ) {
+            Ok(()) => eprintln!("{}", self.skin.inline(&format!("*exported to {path}*"))),
+            Err(e) => eprintln!("failed to export to {path}: {e}"),
+        }
+    }
+
+    // fork the conversation into a new session carrying on from here, the current one is saved
+    // as it is and can be switched back to with `/switch`
+    fn branch(&mut self, name: &str) {
+        if let Err(e) = paths::check_session_name(name) {
+            eprintln!("{e}");
+            return;
+        }
+        if name == self.session || paths::session_file(name).exists() {
+            eprintln!("a session named {name} already exists, see `/branch list`");
+            return;
+        }
+        self.save_session();
+        self.parent = Some(std::mem::replace(&mut self.session, name.to_string()));
+        self.save_session();
+        eprintln!(
+            "{}",
+            self.skin.inline(&format!(
+                "*branched into {name}, `/switch {}` goes back*",
+                self.parent.as_deref().unwrap_or_default()
+            ))
+        );
+    }
+
+    // save the current session and carry on with the saved session `name`
+    fn switch_session(&mut self, name: &str) {
+        if name.is_empty() {
+            eprintln!("usage: /switch <session>");
+            return;
+        }
+        if name == self.session {
+            return;
+        }
+        if let Err(e) = paths::check_session_name(name) {
+            eprintln!("{e}");
+            return;
+        }
+        let path = paths::session_file(name);
+        let loaded = match Session::load(&path) {
+            Ok(session) => {
+                if session.is_newer() {
+                    warn_newer_session(&path, session.version);
+                }
+                session
+            }
+            Err(e) => {
+                eprintln!("failed to load the session from {}: {e}", path.display());
+                return;
+            }
+        };
+        self.save_session();
+        self.history = loaded.to_history();
+        self.parent = loaded.parent;
+        self.session = name.to_string();
+        self.trimmed = 0;
+        eprintln!(
+            "{}",
+            self.skin.inline(&format!(
+                "*switched to {name}, {} messages*",
+                self.history.len()
+            ))
+        );
+    }
+
+    // the saved sessions, with the one they were branched from
+    fn print_branches(&self) {
+        let mut md = String::new();
+        for name in Session::list().unwrap_or_default() {
+            let parent = match Session::load(&paths::session_file(&name)) {
+                Ok(Session {
+                    parent: Some(parent),
+                    ..
+                }) => format!(" branched from *{parent}*"),
+                _ => String::new(),
+            };
+            let current = if name == self.session {
+                " *(current)*"
+            } else {
+                ""
+            };
+            md.push_str(&format!("* **{name}**{current}{parent}\n"));
+        }
+        if md.is_empty() {
+            md.push_str("*no saved sessions*\n");
+        }
+        eprintln!("{}", self.skin.term_text(&md));
+    }
+
+    // export every saved session as a markdown transcript into `dir`, the current one saved first
+    fn save_all(&mut self, dir: &str) {
+        if dir.is_empty() {
+            eprintln!("usage: /save-all <dir>");
+            return;
+        }
+        self.save_session();
+        match session::export_all(Path::new(dir), self.export_system, self.role_tag) {
+            Ok(count) => eprintln!(
+                "{}",
+                self.skin
+                    .inline(&format!("*exported {count} sessions to {dir}*"))
+            ),
+            Err(e) => eprintln!("failed to export the sessions: {e}"),
+        }
+    }
+
+    // the clipboard as the prompt, after the optional instruction
+    fn paste(&self, instruction: &str) -> Handled {
+        let text = match clipboard::read() {
+            Ok(text) => text,
+            Err(e) => {
+                eprintln!("{e}");
+                return Handled::Done;
+            }
+        };
+        if text.trim().is_empty() {
+            eprintln!("{}", self.skin.inline("*the clipboard is empty*"));
+            return Handled::Done;
+        }
+        if instruction.is_empty() {
+            Handled::Send(text)
+        } else {
+            Handled::Send(format!("{instruction}\n\n{text}"))
+        }
+    }
+
+    fn print_personas(&self) {
+        let mut md = String::new();
+        for (name, persona) in self.personas.iter() {
+            md.push_str(&format!("* **{name}** {}\n", persona.description));
+        }
+        if md.is_empty() {
+            md = format!(
+                "*no personas defined, add them to {}*\n",
+                paths::personas_file().display()
+            );
+        }
+        eprintln!("{}", self.skin.term_text(&md));
+    }
+
+    // replace the prompt of the current persona with this one's, keeping the other system
+    // messages (the developer message, the context files). false if there's no such persona
+    pub fn switch_persona(&mut self, name: &str) -> bool {
+        let Some(persona) = self.personas.get(name) else {
+            eprintln!("unknown persona: {name}, see `/persona list`");
+            return false;
+        };
+        let message = ChatCompletionRequestSystemMessageArgs::default()
+            .content(persona.prompt.clone())
+            .build()
+            .unwrap()
+            .into();
+        let current = self.history.iter().position(|m| {
+            matches!(m, ChatCompletionRequestMessage::System(_))
+                && self
+                    .personas
+                    .values()
+                    .any(|persona| persona.prompt == message_text(m))
+        });
+        match current {
+            Some(i) => self.history[i] = message,
+            None => self.history.insert(0, message),
+        }
+        eprintln!("{}", self.skin.inline(&format!("*persona: {name}*")));
+        true
+    }
+
+    // list the history, telling apart the messages that still fit in the context window
+    fn print_context(&self) {
+        let mut md = String::new();
+        for (i, message) in self.history.iter().enumerate() {
+            let state = if self.is_trimmed(i) {
+                "trimmed"
+            } else {
+                "in window"
+            };
+            let text: String = message_text(message).chars().take(60).collect();
+            md.push_str(&format!(
+                "* `{i}` **{}** *{state}* {}\n",
+                message_role(message),
+                text.replace('\n', " ")
+            ));
+        }
+        if md.is_empty() {
+            md.push_str("*the history is empty*\n");
+        }
+        eprintln!("{}", self.skin.term_text(&md));
+    }
+
+    // the whole history as it's sent, each message under its index and role
+    fn print_history(&self) {
+        if self.history.is_empty() {
+            eprintln!("{}", self.skin.inline("*the history is empty*"));
+            return;
+        }
+        for i in 0..self.history.len() {
+            self.print_message(i);
+        }
+    }
+
+    // the last `count` messages of a resumed conversation, the system ones aside
+    pub fn print_tail(&self, count: usize) {
+        let shown: Vec<usize> = (0..self.history.len())
+            .filter(|&i| !matches!(self.history[i], ChatCompletionRequestMessage::System(_)))
+            .collect();
+        for &i in &shown[shown.len().saturating_sub(count)..] {
+            self.print_message(i);
+        }
+    }
+
+    // a history message under its index and role
+    fn print_message(&self, i: usize) {
+        let message = &self.history[i];
+        let state = if self.is_trimmed(i) {
+            " *(trimmed, not sent)*"
+        } else {
+            ""
+        };
+        // on stdout with the messages, for them to stay in order when piped
+        println!(
+            "{}",
+            self.skin
+                .inline(&format!("**`{i}` {}**{state}", message_role(message)))
+        );
+        self.print_markdown(message_text(message));
+        println!();
+    }
+
+    // forget the conversation, the system prompt (eg: from a persona) too with `all`
+    fn clear(&mut self, all: bool) {
+        let before = self.history.len();
+        if all {
+            self.history.clear();
+        } else {
+            self.history
+                .retain(|m| matches!(m, ChatCompletionRequestMessage::System(_)));
+        }
+        self.trimmed = 0;
+        let kept = if self.history.is_empty() {
+            String::new()
+        } else {
+            format!(", kept {} system messages", self.history.len())
+        };
+        eprintln!(
+            "{}",
+            self.skin.inline(&format!(
+                "*cleared {} messages{kept}*",
+                before - self.history.len()
+            ))
+        );
+    }
+
+    // remove the message `n` (as numbered by /history) and show what's left
+    fn delete_message(&mut self, n: &str) {
+        let Ok(n) = n.parse::<usize>() else {
+            eprintln!("usage: /delete <n>, the index shown by /history");
+            return;
+        };
+        if n >= self.history.len() {
+            eprintln!("no message {n}, the history has {}", self.history.len());
+            return;
+        }
+        self.history.remove(n);
+        if n < self.trimmed {
+            self.trimmed -= 1;
+        }
+        self.print_history();
+        // the models expect the user and the assistant to take turns
+        let around = (
+            n.checked_sub(1).and_then(|i| self.history.get(i)),
+            self.history.get(n),
+        );
+        if let (Some(before), Some(after)) = around {
+            let role = message_role(before);
+            if role != "system" && role == message_role(after) {
+                eprintln!(
+                    "{}",
+                    self.skin.inline(&format!(
+                        "*⚠ messages {} and {n} are both from the {role} now*",
+                        n - 1
+                    ))
+                );
+            }
+        }
+    }
+
+    // carry on with the last response (eg: cut off by max_tokens), the rest is merged into it so
+    // the history keeps a single answer
+    async fn continue_last(&mut self) {
+        let Some(ChatCompletionRequestMessage::Assistant(_)) = self.history.last() else {
+            eprintln!("{}", self.skin.inline("*no response to continue*"));
+            return;
+        };
+        let Some(last) = self.history.pop() else {
+            return;
+        };
+        let partial = message_text(&last).to_string();
+        let body = self.continue_body(&partial);
+        self.continuing = Some(partial);
+        let stream = self.request(body).await;
+        if !self.respond(stream).await {
+            self.continuing = None;
+            self.history.push(last);
+        }
+    }
+
+    // ask for the gist of the last response, as a turn of its own
+    async fn tldr(&mut self) {
+        if !self
+            .history
+            .iter()
+            .any(|m| matches!(m, ChatCompletionRequestMessage::Assistant(_)))
+        {
+            eprintln!("{}", self.skin.inline("*no response to summarize*"));
+            return;
+        }
+        println!("{}", self.skin.inline("**TL;DR**"));
+        let stream = self
+            .send_message(
+                "Summarize your last response in a few short bullet points, nothing else."
+                    .to_string(),
+            )
+            .await;
+        self.respond(stream).await;
+    }
+
+    // summarize the history but its last `keep` turns, and replace it with the summary. the
+    // summary is an assistant message, it goes with the conversation and not the system prompt
+    async fn compact(&mut self, keep: usize) {
+        let Some(split) = self.turn_start(keep) else {
+            eprintln!("{}", self.skin.inline("*nothing to compact*"));
+            return;
+        };
+        let (system, old): (Vec<_>, Vec<_>) = self.history[..split]
+            .iter()
+            .cloned()
+            .partition(|m| matches!(m, ChatCompletionRequestMessage::System(_)));
+        let mut messages = system.clone();
+        messages.extend(old.iter().cloned());
+        messages.push(
+            ChatCompletionRequestUserMessageArgs::default()
+                .content(
+                    "Summarize the conversation so far concisely, keeping the facts, decisions \
+                     and open questions needed to carry it on.",
+                )
+                .build()
+                .unwrap()
+                .into(),
+        );
+        // the same request as the prompts', whatever the model takes
+        let body = self.request_body(messages);
+        let summary = match self.request(body).await {
+            Ok(stream) => batch::collect(stream).await,
+            Err(e) => Err(api::describe_error(&e)),
+        };
+        let summary = match summary {
+            Ok(summary) if !summary.trim().is_empty() => summary,
+            Ok(_) => {
+                print_error(
+                    "failed to summarize the conversation: the summary was empty",
+                    None,
+                );
+                return;
+            }
+            Err(e) => {
+                print_error(&format!("failed to summarize the conversation: {e}"), None);
+                return;
+            }
+        };
+        self.print_markdown(&summary);
+        if !confirm(&format!(
+            "Replace the {} oldest messages with this summary?",
+            old.len()
+        )) {
+            return;
+        }
+
+        let summary = ChatCompletionRequestAssistantMessageArgs::default()
+            .content(format!("Summary of the conversation so far:\n{summary}"))
+            .build()
+            .unwrap()
+            .into();
+        let kept = self.history.split_off(split);
+        self.history = system;
+        self.history.push(summary);
+        self.history.extend(kept);
+        eprintln!(
+            "{}",
+            self.skin.inline(&format!(
+                "*compacted {} messages into a summary*",
+                old.len()
+            ))
+        );
+    }
+
+    // index in the history where the `n`th last turn starts, None when there are no older turns
+    fn turn_start(&self, n: usize) -> Option<usize> {
+        let users: Vec<usize> = (0..self.history.len())
+            .filter(|&i| matches!(self.history[i], ChatCompletionRequestMessage::User(_)))
+            .collect();
+        if users.len() <= n {
+            return None;
+        }
+        // keeping no turn compacts the whole history
+        Some(if n == 0 {
+            self.history.len()
+        } else {
+            users[users.len() - n]
+        })
+    }
+}
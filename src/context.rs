@@ -0,0 +1,108 @@
+use ignore::{overrides::OverrideBuilder, WalkBuilder};
+use std::{fs, path::PathBuf};
+
+// the files matching `patterns` (eg: "src/**/*.rs") as labeled fenced blocks, to be sent along
+// with the conversation. they're read in order until ~`budget` tokens, the file going over is
+// truncated and the ones after it are left out, with a warning. the files ignored by git (and
+// the hidden ones) are skipped unless `gitignore` is false
+pub fn collect(patterns: &[String], budget: usize, gitignore: bool) -> Result<String, String> {
+    let mut files = Vec::new();
+    for pattern in patterns {
+        let found = matching_files(pattern, gitignore)?;
+        if found.is_empty() {
+            eprintln!("--context {pattern}: no matching file");
+        }
+        for file in found {
+            if !files.contains(&file) {
+                files.push(file);
+            }
+        }
+    }
+
+    let mut context = String::new();
+    let mut left = budget;
+    for (i, file) in files.iter().enumerate() {
+        let Ok(mut text) = fs::read_to_string(file) else {
+            eprintln!("--context: skipping {}, not a text file", file.display());
+            continue;
+        };
+        // ~4 chars a token, the same estimate as for the history
+        if text.chars().count() / 4 > left {
+            text = text.chars().take(left * 4).collect();
+            eprintln!(
+                "⚠ the context files are over {budget} tokens, {} was truncated and {} more left out",
+                file.display(),
+                files.len() - i - 1
+            );
+            left = 0;
+        } else {
+            left -= text.chars().count() / 4;
+        }
+        let lang = file.extension().and_then(|ext| ext.to_str()).unwrap_or("");
+        let fence = fence_for(&text);
+        context.push_str(&format!(
+            "{}:\n{fence}{lang}\n{}\n{fence}\n\n",
+            file.display(),
+            text.trim_end()
+        ));
+        if left == 0 {
+            break;
+        }
+    }
+    Ok(context)
+}
+
+// the files under the literal start of `pattern` matching the rest of it, sorted
+fn matching_files(pattern: &str, gitignore: bool) -> Result<Vec<PathBuf>, String> {
+    let Some((base, glob)) = split_pattern(pattern) else {
+        // a plain path, taken even when it's ignored since it was asked for by name
+        let path = PathBuf::from(pattern);
+        return Ok(if path.is_file() {
+            vec![path]
+        } else {
+            Vec::new()
+        });
+    };
+    let mut overrides = OverrideBuilder::new(&base);
+    overrides
+        .add(&glob)
+        .map_err(|e| format!("--context {pattern}: {e}"))?;
+    let overrides = overrides
+        .build()
+        .map_err(|e| format!("--context {pattern}: {e}"))?;
+    let mut files: Vec<PathBuf> = WalkBuilder::new(&base)
+        .standard_filters(gitignore)
+        .overrides(overrides)
+        .build()
+        .filter_map(Result::ok)
+        .filter(|entry| entry.file_type().is_some_and(|kind| kind.is_file()))
+        .map(|entry| {
+            let path = entry.into_path();
+            path.strip_prefix("./").map_or(path.clone(), PathBuf::from)
+        })
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
+// "src/**/*.rs" is walked from "src" for "/**/*.rs" (anchored there, like "*.rs" only matches
+// the files right in the base), None for a plain path
+fn split_pattern(pattern: &str) -> Option<(PathBuf, String)> {
+    let parts: Vec<&str> = pattern.split('/').collect();
+    let literal = parts
+        .iter()
+        .position(|part| part.contains(['*', '?', '[', '{']))?;
+    let base = parts[..literal].join("/");
+    let base = match base.as_str() {
+        "" if pattern.starts_with('/') => PathBuf::from("/"),
+        "" => PathBuf::from("."),
+        _ => PathBuf::from(base),
+    };
+    Some((base, format!("/{}", parts[literal..].join("/"))))
+}
+
+// a fence longer than any run of backticks in the text, for it not to be closed early
+pub fn fence_for(text: &str) -> String {
+    let longest = text.split(|c| c != '`').map(str::len).max().unwrap_or(0);
+    "`".repeat(longest.max(2) + 1)
+}
@@ -3,127 +3,1282 @@ use async_openai::{
     error::OpenAIError,
     types::{
         ChatCompletionRequestAssistantMessageArgs, ChatCompletionRequestMessage,
-        ChatCompletionRequestUserMessageArgs, ChatCompletionResponseStream,
-        CreateChatCompletionRequestArgs,
+        ChatCompletionRequestSystemMessageArgs, ChatCompletionRequestUserMessageArgs,
+        ChatCompletionRequestUserMessageContent, CreateChatCompletionRequestArgs,
+        CreateModerationRequestArgs,
     },
     Client,
 };
-use clap::Parser;
-use futures::StreamExt;
+use clap::{Parser, ValueEnum};
+use futures::{Stream, StreamExt};
+use serde_derive::{Deserialize, Serialize};
 use std::{
-    env,
-    io::{stdout, Write},
-    panic, println,
+    collections::{BTreeSet, VecDeque},
+    io::{stdout, IsTerminal, StdoutLock, Write},
+    panic,
+    pin::Pin,
+    println,
     process::exit,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, OnceLock,
+    },
+    time::{Duration, Instant},
 };
 use termimad::crossterm::{
-    cursor::{self, MoveLeft, MoveToPreviousLine},
-    event::{self, Event},
-    execute, queue,
-    style::{self, Color::*},
-    terminal::{disable_raw_mode, enable_raw_mode, size, Clear, ClearType},
+    cursor::{self, MoveToColumn, MoveToPreviousLine},
+    event::{
+        self, Event, EventStream, KeyEventKind, KeyboardEnhancementFlags,
+        PopKeyboardEnhancementFlags, PushKeyboardEnhancementFlags,
+    },
+    execute, queue, style,
+    terminal::{
+        disable_raw_mode, enable_raw_mode, size, supports_keyboard_enhancement, Clear, ClearType,
+    },
     ExecutableCommand,
 };
 use termimad::*;
+use unicode_width::UnicodeWidthStr;
+
+mod api;
+mod batch;
+mod clipboard;
+mod commands;
+mod context;
+mod paths;
+mod persona;
+mod picker;
+mod render;
+mod serve;
+mod session;
+mod settings;
+mod tui;
+use api::{ApiConfig, ChatStream, RateLimits};
+use commands::Handled;
+use persona::Personas;
+use render::{LightStyle, StreamWrap, ThinkSplitter};
+use session::Session;
+use settings::{Effective, ModelParams};
+
+// tokens reserved for the reply, the rest of the context window is left for the history
+const MAX_TOKENS: u16 = 1234;
 
 #[tokio::main]
 async fn main() {
     panic::set_hook(Box::new(|info| {
-        disable_raw_mode().unwrap();
-        println!("Error: {:#?}", info);
+        tui::leave();
+        let _ = disable_raw_mode();
+        // where it happened, for the bug reports
+        print_error(&format!("cli-gpt crashed, {info}"), None);
     }));
+    // supervisors and containers stop the process with SIGTERM rather than ctrl-c. what's
+    // pending is only there with --autosave-interval, without it nothing is saved but by the
+    // session commands. the terminal is restored through stderr, stdout stays locked for the
+    // whole of a streamed response
+    tokio::spawn(async {
+        terminated().await;
+        session::save_pending();
+        let mut err = std::io::stderr();
+        tui::leave_on(&mut err);
+        let _ = disable_raw_mode();
+        let _ = execute!(err, cursor::Show);
+        exit(143);
+    });
     let mut app = App::new();
     app.run().await;
 }
 
+fn warn_newer_session(path: &std::path::Path, version: u32) {
+    eprintln!(
+        "⚠ {} was saved by a newer version of cli-gpt (session version {version}, this one supports up to {}), it's loaded best-effort and won't be overwritten",
+        path.display(),
+        session::VERSION
+    );
+}
+
+// resolves when the process is asked to terminate, never where there's no such signal
+async fn terminated() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        if let Ok(mut term) = signal(SignalKind::terminate()) {
+            term.recv().await;
+            return;
+        }
+    }
+    #[cfg(windows)]
+    {
+        if let Ok(mut close) = tokio::signal::windows::ctrl_close() {
+            close.recv().await;
+            return;
+        }
+    }
+    std::future::pending::<()>().await
+}
+
 // args for the app, can be passed in from the command line
 #[derive(Parser, Debug)]
 struct AppArgs {
+    /// print the version, the build and the default model, then exit
+    #[arg(short = 'V', long, default_value_t = false)]
+    version: bool,
     #[arg(short = '4', long, default_value_t = false)]
     enable_gpt4: bool,
+    /// profile of the config file to use
+    #[arg(short, long, default_value = settings::DEFAULT_PROFILE)]
+    profile: String,
+    /// directory holding the config file, personas and sessions, instead of the platform ones
+    #[arg(long)]
+    config_dir: Option<std::path::PathBuf>,
+    /// print where each setting comes from and exit
+    #[arg(long, default_value_t = false)]
+    config_info: bool,
+    /// print the effective settings (toml by default, or json) and exit
+    #[arg(long, value_enum, num_args = 0..=1, default_missing_value = "toml", value_name = "FORMAT")]
+    print_config: Option<ConfigFormat>,
+    /// export every saved session as a transcript into DIR and exit
+    #[arg(long, value_name = "DIR")]
+    export_all: Option<std::path::PathBuf>,
+    /// print the models of the api (all of them, or the chat ones) and exit
+    #[arg(long, value_enum, num_args = 0..=1, default_missing_value = "all", value_name = "FILTER")]
+    list_models: Option<ModelFilter>,
+    /// chat model to use, takes precedence over -4
+    #[arg(short, long)]
+    model: Option<String>,
+    /// sampling temperature, overrides the model's default from the config
+    #[arg(long)]
+    temperature: Option<f32>,
+    /// nucleus sampling, overrides the model's default from the config
+    #[arg(long)]
+    top_p: Option<f32>,
+    /// tokens reserved for the reply, overrides the model's default from the config. sent as
+    /// max_tokens or max_completion_tokens, whichever the model takes
+    #[arg(long)]
+    max_tokens: Option<u16>,
+    /// field the reply's token limit is sent in, by default max_completion_tokens for the models
+    /// known or found to reject max_tokens
+    #[arg(long, value_enum, value_name = "FIELD")]
+    tokens_field: Option<TokensField>,
+    /// instructions for the model, sent with the developer or system role
+    #[arg(long, value_name = "TEXT")]
+    developer: Option<String>,
+    /// role of the instructions, by default developer for the models preferring it (o1, o3...)
+    #[arg(long, value_enum)]
+    instruction_role: Option<InstructionRole>,
+    /// ask before sending a request estimated over this many tokens, such a request is refused
+    /// when there's no one to ask (--serve, stdin piped)
+    #[arg(long, default_value_t = 50_000, value_name = "TOKENS")]
+    confirm_over: usize,
+    /// never ask before sending a request, for scripts
+    #[arg(short, long, default_value_t = false)]
+    yes: bool,
+    /// how hard reasoning models (o1, o3, gpt-5...) think before answering, overrides the
+    /// model's default from the config. ignored by the other models
+    #[arg(long, value_enum)]
+    reasoning_effort: Option<ReasoningEffort>,
+    /// how long the answers of the gpt-5 models are, ignored by the other models
+    #[arg(long, value_enum)]
+    verbosity: Option<Verbosity>,
+    /// name of the session, saved as <data dir>/sessions/<name>.json
+    #[arg(long, default_value = "default", value_parser = parse_session_name)]
+    session: String,
+    /// auto-save the session every N turns (eg: 5) or every N seconds (eg: 30s), the turns in
+    /// between are saved when quitting or terminated. without it, the session is only saved
+    /// by /branch, /switch and /save-all
+    #[arg(long, value_parser = parse_autosave)]
+    autosave_interval: Option<Autosave>,
+    /// cap on the size of the session file, the oldest turns are left out of it when exceeded
+    #[arg(long, default_value_t = 1024 * 1024)]
+    max_history_bytes: usize,
+    /// resume the saved session instead of starting a new one
+    #[arg(short = 'c', long = "continue", default_value_t = false)]
+    resume: bool,
+    /// when resuming a conversation, print its last N messages first (the last response by
+    /// default), 0 for none
+    #[arg(long, default_value_t = 1, value_name = "N")]
+    tail: usize,
+    /// start from a transcript written by /export (in any --role-tag style), eg: after editing it
+    #[arg(long, value_name = "FILE")]
+    continue_from: Option<std::path::PathBuf>,
+    /// json array of `{role, content}` messages to start the conversation with (eg: few-shot)
+    #[arg(long, value_name = "FILE")]
+    messages_file: Option<std::path::PathBuf>,
+    /// start with the system prompt of this persona
+    #[arg(long)]
+    persona: Option<String>,
+    /// send the files matching GLOB (eg: "src/**/*.rs") along, can be repeated
+    #[arg(long, value_name = "GLOB")]
+    context: Vec<String>,
+    /// at most ~TOKENS of --context files, the rest is truncated or left out. lowered to what
+    /// the model's context window leaves after the prompts and max_tokens
+    #[arg(long, default_value_t = 20_000, value_name = "TOKENS")]
+    context_budget: usize,
+    /// include the --context files ignored by git and the hidden ones
+    #[arg(long, default_value_t = false)]
+    no_gitignore: bool,
+    /// toml file defining the personas, defaults to <config dir>/personas.toml
+    #[arg(long)]
+    personas_file: Option<std::path::PathBuf>,
+    /// text put before every prompt sent, overriding the config's one
+    #[arg(long, value_name = "TEXT")]
+    prepend: Option<String>,
+    /// text put after every prompt sent (eg: "Answer concisely."), overriding the config's one
+    #[arg(long, value_name = "TEXT")]
+    append: Option<String>,
+    /// how the roles of the messages are tagged in the /export transcripts, read back by
+    /// --continue-from whatever the style
+    #[arg(long, value_enum, default_value_t = RoleTag::Heading)]
+    role_tag: RoleTag,
+    /// write the system prompt to the /export transcripts, left out of them by default
+    #[arg(long, default_value_t = false)]
+    export_system: bool,
+    /// leave the system prompt (the persona's, the developer message, the --context files) out
+    /// of the saved session. a /compact summary is kept
+    #[arg(long, default_value_t = false)]
+    no_save_system: bool,
+    /// key sending the prompt, detected from the terminal by default
+    #[arg(long, value_enum)]
+    send_key: Option<SendKey>,
+    /// break the input lines longer than N chars at their last space, eg: for long pastes
+    #[arg(long, value_name = "N")]
+    max_line: Option<usize>,
+    /// print links as `text (url)` instead of clickable OSC 8 hyperlinks
+    #[arg(long, default_value_t = false)]
+    no_hyperlinks: bool,
+    /// show the reasoning of the models sending it (apart, or in <think> tags), hidden otherwise
+    #[arg(long, default_value_t = false)]
+    show_reasoning: bool,
+    /// when the connection drops mid-response, ask the model to carry on, up to N times (3)
+    #[arg(long, num_args = 0..=1, default_missing_value = "3", value_name = "ATTEMPTS")]
+    auto_continue: Option<u32>,
+    /// print what's left of the rate limits (requests, tokens) and when they reset, before
+    /// every response
+    #[arg(long, default_value_t = false)]
+    show_rate_limit: bool,
+    /// wait for the rate limits to reset when the last response tells they're used up, rather
+    /// than running into them
+    #[arg(long, default_value_t = false)]
+    pace: bool,
+    /// flush the streamed text every N deltas (eg: 8) or every N milliseconds (eg: 30ms),
+    /// rather than after each delta, for fewer writes to the terminal on fast streams
+    #[arg(long, value_parser = parse_flush_every, default_value = "1", value_name = "N|Nms")]
+    flush_every: FlushEvery,
+    /// type the response out at one character every MS milliseconds, rather than in bursts
+    #[arg(long, value_name = "MS")]
+    typewriter: Option<u64>,
+    /// print the prompt as a quote before its response, eg: for the prompts piped in. the
+    /// --json lines always have it
+    #[arg(long, visible_alias = "echo", default_value_t = false)]
+    echo_prompt: bool,
+    /// show what changed from the previous response when retrying with /retry
+    #[arg(long, default_value_t = false)]
+    diff: bool,
+    /// run the prompts through the moderation endpoint first, the flagged ones aren't sent
+    #[arg(long, default_value_t = false)]
+    moderate: bool,
+    /// how the tables too wide for the terminal are shown
+    #[arg(long, value_enum, default_value_t = TableFit::Wrap)]
+    table_fit: TableFit,
+    /// wrap the streamed text at the words, rather than leaving it to the terminal
+    #[arg(long, default_value_t = false)]
+    wrap: bool,
+    /// also write the responses to FILE as they stream, as plain markdown
+    #[arg(long, value_name = "FILE")]
+    tee: Option<std::path::PathBuf>,
+    /// copy every response to the clipboard once it's complete
+    #[arg(long, default_value_t = false)]
+    copy: bool,
+    /// when the model answers nothing, ask again once rather than leaving the prompt unanswered
+    #[arg(long, default_value_t = false)]
+    retry_empty: bool,
+    /// chat in the alternate screen, the conversation scrolling above a status line
+    #[arg(long, default_value_t = false)]
+    tui: bool,
+    /// have the model fill the gap between the prompt and TEXT, eg: to complete code
+    #[arg(long, value_name = "TEXT")]
+    suffix: Option<String>,
+    /// keep the streamed text as it is, lightly styled (bold headings, colored code), rather than
+    /// clearing it to print it again as markdown. it all stays in the scrollback
+    #[arg(long, visible_alias = "no-rerender", default_value_t = false)]
+    no_reflow: bool,
+    /// print the responses as plain text, without any markdown syntax: the headings as plain
+    /// lines, the list items starting with dashes and the code blocks indented
+    #[arg(long, default_value_t = false, conflicts_with = "no_reflow")]
+    strip_markdown: bool,
+    /// render the paragraphs, lists, tables and code blocks as markdown as soon as they're
+    /// complete, rather than the whole response at the end
+    #[arg(long, default_value_t = false)]
+    progressive: bool,
+    /// print the word count and reading time under every response
+    #[arg(long, default_value_t = false)]
+    stats: bool,
+    /// print the full details of the errors
+    #[arg(short, long, default_value_t = false)]
+    verbose: bool,
+    /// answer the prompts piped in one by one, split at SEP (`\n---\n` by default), and exit
+    #[arg(long, num_args = 0..=1, default_missing_value = "\\n---\\n", value_name = "SEP")]
+    stdin_prompt_separator: Option<String>,
+    /// start every piped prompt from the initial history, rather than carrying the conversation on
+    #[arg(long, default_value_t = false)]
+    stateless: bool,
+    /// print the responses to the piped prompts as json lines, `{"prompt", "response"}`
+    #[arg(long, default_value_t = false)]
+    json: bool,
+    /// serve a local http endpoint (eg: 127.0.0.1:8080) streaming responses as SSE
+    #[arg(long, value_name = "ADDR")]
+    serve: Option<String>,
+    /// initial prompt, the text piped in is appended to it
     pmt: Vec<String>,
 }
 
+#[derive(ValueEnum, Debug, Clone, Copy)]
+enum ConfigFormat {
+    Toml,
+    Json,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy)]
+enum ModelFilter {
+    All,
+    Chat,
+}
+
+// what's done with the markdown tables wider than the terminal
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+enum TableFit {
+    Wrap,     // termimad wraps the cells
+    Truncate, // the widest columns are cut short with `…`
+    Vertical, // each row becomes a block of `header: value` lines
+}
+
+impl TableFit {
+    fn as_str(&self) -> &'static str {
+        match self {
+            TableFit::Wrap => "wrap",
+            TableFit::Truncate => "truncate",
+            TableFit::Vertical => "vertical",
+        }
+    }
+}
+
+// how the roles of the messages are marked in the exported transcripts
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+enum RoleTag {
+    Heading, // `## You`, `## Assistant` markdown headings
+    Xml,     // `<user>` ... `</user>` lines
+    Chatml,  // `<|im_start|>user` ... `<|im_end|>`
+}
+
+impl RoleTag {
+    fn as_str(&self) -> &'static str {
+        match self {
+            RoleTag::Heading => "heading",
+            RoleTag::Xml => "xml",
+            RoleTag::Chatml => "chatml",
+        }
+    }
+}
+
+// the key stroke sending the prompt, ctrl-enter is only told apart from enter by the
+// terminals supporting the kitty keyboard protocol
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+enum SendKey {
+    CtrlEnter,
+    CtrlD,
+    DoubleEnter,
+    Enter,
+}
+
+impl SendKey {
+    fn detect() -> Self {
+        if stdout().is_terminal() && supports_keyboard_enhancement().unwrap_or(false) {
+            SendKey::CtrlEnter
+        } else {
+            SendKey::DoubleEnter
+        }
+    }
+
+    fn describe(&self) -> &'static str {
+        match self {
+            SendKey::CtrlEnter => "ctrl-enter",
+            SendKey::CtrlD => "ctrl-d",
+            SendKey::DoubleEnter => "two continuous enters",
+            SendKey::Enter => "enter",
+        }
+    }
+}
+
+// the role the instructions (system prompt, persona...) are sent with, the newer models prefer
+// developer to system
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+enum InstructionRole {
+    System,
+    Developer,
+}
+
+impl InstructionRole {
+    fn for_model(model: &str) -> Self {
+        // the first reasoning models take no instructions at all, let alone a developer message
+        let legacy = model.starts_with("o1-mini") || model.starts_with("o1-preview");
+        if is_reasoning_model(model) && !legacy {
+            InstructionRole::Developer
+        } else {
+            InstructionRole::System
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            InstructionRole::System => "system",
+            InstructionRole::Developer => "developer",
+        }
+    }
+}
+
+#[derive(ValueEnum, Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum ReasoningEffort {
+    Low,
+    Medium,
+    High,
+}
+
+impl ReasoningEffort {
+    fn as_str(&self) -> &'static str {
+        match self {
+            ReasoningEffort::Low => "low",
+            ReasoningEffort::Medium => "medium",
+            ReasoningEffort::High => "high",
+        }
+    }
+}
+
+// how long the answers of the gpt-5 models are
+#[derive(ValueEnum, Debug, Clone, Copy)]
+enum Verbosity {
+    Low,
+    Medium,
+    High,
+}
+
+impl Verbosity {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Verbosity::Low => "low",
+            Verbosity::Medium => "medium",
+            Verbosity::High => "high",
+        }
+    }
+}
+
+// the request field holding the token limit of the reply, the newer models deprecate
+// max_tokens in favor of max_completion_tokens
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+enum TokensField {
+    MaxTokens,
+    MaxCompletionTokens,
+}
+
+impl TokensField {
+    fn as_str(&self) -> &'static str {
+        match self {
+            TokensField::MaxTokens => "max_tokens",
+            TokensField::MaxCompletionTokens => "max_completion_tokens",
+        }
+    }
+}
+
+// the parameters a request is sent with
+struct Params {
+    temperature: Option<f32>,
+    top_p: Option<f32>,
+    max_tokens: u16,
+    reasoning_effort: Option<ReasoningEffort>,
+    system_prompt: Option<String>,
+}
+
+// a response being streamed
+#[derive(Default)]
+struct Streamed {
+    answer: String, // the content, it goes to the history
+    // what was written below the reasoning, the answer along with the errors and notes met
+    // on the way, it's all cleared before the answer is rendered as markdown
+    shown: String,
+    reasoning: bool, // the reasoning is being written
+    wrap: Option<StreamWrap>,
+    light: Option<LightStyle>, // with --no-reflow
+    // printed again under the rendered answer, with their details
+    errors: Vec<(String, String)>,
+    waiting: bool,   // the waiting status line is shown
+    rendered: usize, // the length of the answer already rendered as markdown
+    truncated: bool, // cut off by max_tokens
+}
+
+impl Streamed {
+    // erase the waiting status line, before anything else is written in its place
+    fn clear_waiting(&mut self, lock: &mut StdoutLock) {
+        if std::mem::take(&mut self.waiting) {
+            write!(lock, "\r").unwrap();
+            let _ = queue!(lock, Clear(ClearType::CurrentLine));
+        }
+    }
+
+    // write something else than the answer (eg: an error), after what's held back for wrapping
+    fn write_note(&mut self, lock: &mut StdoutLock, note: &str) {
+        self.clear_waiting(lock);
+        let held = self
+            .wrap
+            .as_mut()
+            .map(StreamWrap::finish)
+            .unwrap_or_default();
+        let styled = match self.light.as_mut() {
+            Some(light) => light.push(&held) + light.end() + note,
+            None => held.clone() + note,
+        };
+        write!(lock, "{}", styled.replace('\n', "\r\n")).unwrap();
+        self.shown.push_str(&(held + note));
+    }
+}
+
+// held while a request is sent and its response streamed, so no two run at once: their
+// output would interleave and both would push to the history. it's let go when dropped
+struct InFlight(Arc<AtomicBool>);
+
+impl InFlight {
+    // None when a request is already in flight
+    fn begin(flag: &Arc<AtomicBool>) -> Option<Self> {
+        (!flag.swap(true, Ordering::SeqCst)).then(|| Self(flag.clone()))
+    }
+
+    // the stream of the response, holding on to the guard until it's dropped
+    fn hold(self, stream: ChatStream) -> ChatStream {
+        Box::pin(stream.inspect(move |_| {
+            let _ = &self;
+        }))
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
+
+// how a streamed response ended
+#[derive(PartialEq)]
+enum StreamEnd {
+    Answer,
+    Empty,   // the model answered nothing but whitespace
+    Nothing, // cancelled or failed before any answer
+}
+
+// how often the session is saved to disk while chatting
+#[derive(Debug, Clone, Copy)]
+enum Autosave {
+    Turns(u32),
+    Every(Duration),
+}
+
+fn parse_session_name(s: &str) -> Result<String, String> {
+    paths::check_session_name(s).map(|()| s.to_string())
+}
+
+fn parse_autosave(s: &str) -> Result<Autosave, String> {
+    match s.strip_suffix('s') {
+        Some(secs) => secs
+            .parse()
+            .map(|secs| Autosave::Every(Duration::from_secs(secs)))
+            .map_err(|e| e.to_string()),
+        None => s.parse().map(Autosave::Turns).map_err(|e| e.to_string()),
+    }
+}
+
+// how often the streamed text is flushed to the terminal
+#[derive(Debug, Clone, Copy)]
+enum FlushEvery {
+    Deltas(u32),
+    Every(Duration),
+}
+
+fn parse_flush_every(s: &str) -> Result<FlushEvery, String> {
+    match s.strip_suffix("ms") {
+        Some(ms) => ms
+            .parse()
+            .map(|ms| FlushEvery::Every(Duration::from_millis(ms)))
+            .map_err(|e| e.to_string()),
+        None => match s.parse() {
+            Ok(0) => Err("flushing every 0 deltas, use 1 for every delta".to_string()),
+            Ok(deltas) => Ok(FlushEvery::Deltas(deltas)),
+            Err(e) => Err(e.to_string()),
+        },
+    }
+}
+
+// flushes the streamed text at the --flush-every cadence. what's written is flushed at the
+// latest after the interval (or `MAX_UNFLUSHED` when counting the deltas), so the text doesn't
+// hang on the screen when the stream pauses
+struct Flusher {
+    every: FlushEvery,
+    unflushed: u32, // the writes since the last flush
+    deadline: tokio::time::Instant,
+}
+
+const MAX_UNFLUSHED: Duration = Duration::from_millis(50);
+
+impl Flusher {
+    fn new(every: FlushEvery) -> Self {
+        Self {
+            every,
+            unflushed: 0,
+            deadline: tokio::time::Instant::now(),
+        }
+    }
+
+    // count a write, flushing when it's due
+    fn wrote(&mut self, lock: &mut StdoutLock) {
+        if self.unflushed == 0 {
+            let wait = match self.every {
+                FlushEvery::Every(interval) => interval,
+                FlushEvery::Deltas(_) => MAX_UNFLUSHED,
+            };
+            self.deadline = tokio::time::Instant::now() + wait;
+        }
+        self.unflushed += 1;
+        if matches!(self.every, FlushEvery::Deltas(deltas) if self.unflushed >= deltas) {
+            self.flush(lock);
+        }
+    }
+
+    fn flush(&mut self, lock: &mut StdoutLock) {
+        let _ = lock.flush();
+        self.unflushed = 0;
+    }
+}
+
 struct App {
-    client: Client<OpenAIConfig>,               // chatgpt's api sdk client
-    skin: MadSkin, // theme for rendering output messages(etc: MD, code snippet...)
-    model: &'static str, // chatgpt models.(eg: gpt-3.5-turbo, gpt-4-1106-preview)
-    initial_pmt: String, // stands for initial prompt
-    history: Vec<ChatCompletionRequestMessage>, // for storing the chat history
+    client: Client<ApiConfig>, // chatgpt's api sdk client
+    http: reqwest::Client,     // shared with the sdk client, for the requests it can't build
+    skin: MadSkin,             // theme for rendering output messages(etc: MD, code snippet...)
+    model: String,             // chatgpt models.(eg: gpt-3.5-turbo, gpt-4-1106-preview)
+    pick_model: bool,          // no model was asked for, it's picked at startup
+    profile: String,
+    reasoning_effort: Option<ReasoningEffort>,
+    verbosity: Option<Verbosity>,
+    tokens_field: Option<TokensField>, // picked from the model when not set
+    completion_tokens_models: BTreeSet<String>, // found to reject max_tokens
+    instruction_role: Option<InstructionRole>, // picked from the model when not set
+    temperature: Option<f32>,
+    top_p: Option<f32>,
+    max_tokens: Option<u16>,
+    model_params: std::collections::BTreeMap<String, ModelParams>, // per model defaults
+    initial_pmt: String,                                           // stands for initial prompt
+    history: Vec<ChatCompletionRequestMessage>,                    // for storing the chat history
+    typeahead: String, // what was typed while a response was streaming
+    // the oldest history message sent with the last request, after the pinned system messages
+    trimmed: usize,
+    session: String, // name of the session file the history is saved to
+    autosave: Option<Autosave>,
+    parent: Option<String>, // the session this one was branched from
+    max_history_bytes: usize,
+    tail: usize,                 // messages printed when resuming
+    confirm_over: Option<usize>, // tokens a request may take before asking, None with --yes
+    turns_since_save: u32,
+    last_save: Instant,
+    personas: Personas, // named system prompts, switched with `/persona <name>`
+    favorite_models: Vec<String>, // cycled through by `/model`
+    send_key: SendKey,
+    // the input lines are broken past it
+    max_line: Option<usize>,
+    prompt: String, // drawn before the input, eg: `» `
+    greeting: String,
+    waiting: String,         // shown until the response starts, "" for none
+    prepend: Option<String>, // wrapped around every prompt sent, out of the editor's sight
+    append: Option<String>,
+    // expanded in the prompts after the sigil, eg: `;;tldr`
+    aliases: std::collections::BTreeMap<String, String>,
+    alias_sigil: String,
+    export_system: bool, // the system prompt goes to the /export transcripts
+    role_tag: RoleTag,
+    save_system: bool, // the system prompt goes to the session file
+    serve_addr: Option<String>,
+    batch: Option<(String, Vec<String>)>, // the separator and the prompts piped in
+    stateless: bool,
+    json: bool,
+    list_models: Option<ModelFilter>,
+    hyperlinks: bool, // render the links as OSC 8 hyperlinks
+    wrap: bool,       // soft wrap the streamed text
+    table_fit: TableFit,
+    moderate: bool, // check the prompts with the moderation endpoint before sending them
+    diff: bool,     // diff the retried responses against the previous ones
+    echo_prompt: bool,
+    show_reasoning: bool,
+    verbose: bool,
+    stats: bool,        // print the word count and reading time under the responses
+    auto_continue: u32, // attempts at resuming a response cut off by the network, 0 for none
+    typewriter: Option<Duration>, // pace of the streamed characters
+    flush_every: FlushEvery,
+    show_rate_limit: bool,
+    pace: bool,
+    // the rate limits told by the last response, and when
+    rate_limits: Option<(RateLimits, Instant)>,
+    progressive: bool, // render the response a markdown block at a time
+    no_reflow: bool,   // the streamed text is left as is, never rendered as markdown
+    tui: bool,
+    retry_empty: bool,
+    // the responses are printed as plain text rather than rendered
+    strip_markdown: bool,
+    tee: Option<std::fs::File>, // the file the responses are written to as they stream
+    copy: bool,
+    // the last response was cut off by max_tokens
+    truncated: bool,
+    // the response /continue carries on, merged with the next answer
+    continuing: Option<String>,
+    // set while a request is in flight, see `InFlight`
+    in_flight: Arc<AtomicBool>,
+    suffix: Option<String>, // the text after the gap the prompts are the start of
 }
 
 impl App {
     //main loop
     pub async fn run(&mut self) {
-        println!("Tips: two continuous enters for sending.");
+        if let Some(addr) = self.serve_addr.clone() {
+            self.serve(&addr).await;
+            return;
+        }
+        if let Some(filter) = self.list_models {
+            self.print_models(filter).await;
+            return;
+        }
+        if let Some((separator, prompts)) = self.batch.take() {
+            self.notice_ignored_params();
+            self.run_batch(prompts, &separator).await;
+            return;
+        }
+        // only on a terminal, a script goes on with the default model
+        if self.pick_model && std::io::stdin().is_terminal() && stdout().is_terminal() {
+            self.pick_model().await;
+        }
+        if self.tui && stdout().is_terminal() {
+            tui::enter(&self.status_line());
+        }
+        self.notice_ignored_params();
+        println!(
+            "Tips: {} for sending, alt-enter for a new line.",
+            self.send_key.describe()
+        );
         if !self.initial_pmt.is_empty() {
-            if let Ok(stream) = self.send_message(self.initial_pmt.clone()).await {
-                self.streaming_and_rendering_resp(stream).await;
-            };
-        } else {
+            self.submit(self.initial_pmt.clone()).await;
+        } else if self
+            .history
+            .iter()
+            .any(|m| !matches!(m, ChatCompletionRequestMessage::System(_)))
+        {
+            // a greeting would be out of place in a conversation already going on
             eprintln!(
                 "{}",
-                self.skin.term_text("Hello! How can I assist you today?\n")
+                self.skin.inline(&format!(
+                    "*resumed session '{}' ({} messages)*",
+                    self.session,
+                    self.history.len()
+                ))
             );
+            self.print_tail(self.tail);
+        } else if !self.greeting.is_empty() {
+            eprintln!("{}", self.skin.term_text(&format!("{}\n", self.greeting)));
         }
 
         loop {
-            let pmt = Self::read_pmt();
+            let pmt = self.read_pmt(std::mem::take(&mut self.typeahead));
             // print!("\n------\n{:#?}", pmt);
-            if pmt.len() > 1 {
-                if let Ok(stream) = self.send_message(pmt).await {
-                    self.streaming_and_rendering_resp(stream).await;
-                };
+            let pmt = match self.handle_command(&pmt).await {
+                Handled::NotCommand => pmt,
+                Handled::Done => continue,
+                Handled::Send(pmt) => pmt,
+                Handled::Resend(pmt, exchange) => {
+                    let before = self.history.len();
+                    let answered = self.submit(pmt).await;
+                    self.put_back(before, answered, exchange);
+                    continue;
+                }
+            };
+            // a single char (eg: a CJK one) is a prompt, blank lines aren't
+            if !pmt.trim().is_empty() {
+                self.submit(pmt).await;
+            }
+        }
+    }
+
+    // send a prompt and render the response, or tell why there's none, for the initial prompt
+    // and the ones typed alike: ctrl-c cancels the request from its start to the end of the
+    // response, the terminal being restored either way. the `@key=value` overrides the prompt
+    // starts with only apply to this request, its aliases are expanded. false when there was
+    // no response
+    async fn submit(&mut self, pmt: String) -> bool {
+        let (pmt, unknown) = expand_aliases(&pmt, &self.alias_sigil, &self.aliases);
+        for name in unknown {
+            let warning = format!("*⚠ no alias named {name}, left as is*");
+            eprintln!("{}", self.skin.inline(&warning));
+        }
+        let (overrides, pmt) = split_overrides(&pmt);
+        if pmt.is_empty() {
+            eprintln!(
+                "{}",
+                self.skin.inline("*nothing to send after the overrides*")
+            );
+            return false;
+        }
+        let saved = (
+            self.model.clone(),
+            self.temperature,
+            self.top_p,
+            self.max_tokens,
+            self.reasoning_effort,
+        );
+        for (key, value) in overrides {
+            if let Err(e) = self.apply_override(key, value) {
+                eprintln!("{}", self.skin.inline(&format!("*⚠ {e}, ignored*")));
+            }
+        }
+        if self.echo_prompt {
+            print!("{}", self.skin.term_text(&quote(pmt)));
+        }
+        let answered = match self.suffix.clone() {
+            Some(suffix) => self.fill_in(pmt, &suffix).await,
+            None => {
+                let stream = self.send_message(pmt.to_string()).await;
+                self.respond(stream).await
+            }
+        };
+        (
+            self.model,
+            self.temperature,
+            self.top_p,
+            self.max_tokens,
+            self.reasoning_effort,
+        ) = saved;
+        answered
+    }
+
+    // the exchange /edit-last took out, put back in place of the reworded prompt when it got no
+    // answer (eg: a network error, ctrl-c before the response started). `before` is the length
+    // of the history the prompt was sent from
+    fn put_back(
+        &mut self,
+        before: usize,
+        answered: bool,
+        exchange: Vec<ChatCompletionRequestMessage>,
+    ) {
+        if answered {
+            return;
+        }
+        self.history.truncate(before);
+        self.history.extend(exchange);
+    }
+
+    // the chat models have no suffix parameter, they're asked for the text filling the gap
+    // between the prompt and `suffix`. the joined result is printed under the response, false
+    // when there's none
+    async fn fill_in(&mut self, prefix: &str, suffix: &str) -> bool {
+        let pmt = format!(
+            "Write the text that goes between the prefix and the suffix below, so that they join \
+             up. Answer with that text only, without explanations or code fences.\n\n\
+             <prefix>\n{prefix}\n</prefix>\n<suffix>\n{suffix}\n</suffix>"
+        );
+        let stream = self.send_message(pmt).await;
+        if !self.respond(stream).await {
+            return false;
+        }
+        let middle = self.history.last().map(message_text).unwrap_or_default();
+        let joined = format!("{prefix}{}{suffix}", strip_fence(middle));
+        let fence = context::fence_for(&joined);
+        eprintln!("{}", self.skin.inline("*completed:*"));
+        self.print_markdown(&format!("{fence}\n{joined}\n{fence}"));
+        true
+    }
+
+    fn apply_override(&mut self, key: &str, value: &str) -> Result<(), String> {
+        let invalid = |e: &dyn std::fmt::Display| format!("invalid @{key}={value}: {e}");
+        match key {
+            "model" if !value.is_empty() => self.model = value.to_string(),
+            "temp" | "temperature" => {
+                self.temperature = Some(value.parse().map_err(|e| invalid(&e))?)
+            }
+            "top_p" => self.top_p = Some(value.parse().map_err(|e| invalid(&e))?),
+            "max_tokens" => self.max_tokens = Some(value.parse().map_err(|e| invalid(&e))?),
+            "effort" | "reasoning_effort" => {
+                self.reasoning_effort =
+                    Some(ReasoningEffort::from_str(value, true).map_err(|e| invalid(&e))?)
+            }
+            _ => return Err(format!("unknown override @{key}={value}")),
+        }
+        Ok(())
+    }
+
+    // render the response of a request and save the session, or tell why there's none. false
+    // when there was no response
+    async fn respond(&mut self, stream: Result<ChatStream, OpenAIError>) -> bool {
+        match stream {
+            Ok(stream) => {
+                let mut end = self.streaming_and_rendering_resp(stream).await;
+                if end == StreamEnd::Empty && self.retry_empty {
+                    println!("{}", style::Stylize::dim("(empty response, retrying)"));
+                    let body = self.request_body(self.window());
+                    end = match self.request(body).await {
+                        Ok(stream) => self.streaming_and_rendering_resp(stream).await,
+                        Err(e) => {
+                            self.print_api_error("no response", &e);
+                            StreamEnd::Nothing
+                        }
+                    };
+                }
+                if end == StreamEnd::Empty {
+                    println!("{}", style::Stylize::dim("(empty response)"));
+                }
+                if end != StreamEnd::Answer {
+                    return false;
+                }
+                if self.truncated {
+                    let hint = "(cut off at the max tokens, /continue for the rest)";
+                    println!("{}", style::Stylize::dim(hint));
+                }
+                self.autosave();
+                true
+            }
+            Err(e) => {
+                self.print_api_error("no response", &e);
+                false
             }
         }
     }
 
     pub fn new() -> Self {
-        let api_key = match env::var("OPENAI_API_KEY") {
-            Ok(val) => {
+        Self::with_args(AppArgs::parse(), read_stdin())
+    }
+
+    // the app for these args, `piped` being what was piped in
+    fn with_args(args: AppArgs, piped: String) -> Self {
+        if args.version {
+            print_version();
+            exit(0);
+        }
+        if let Some(dir) = args.config_dir.clone() {
+            paths::set_config_dir(dir);
+        }
+        let settings = match settings::load_settings(&args.profile) {
+            Ok(settings) => settings,
+            Err(e) => {
+                eprintln!("failed to load the config: {e}");
+                exit(1);
+            }
+        };
+
+        let effective = Effective::resolve(
+            &args.profile,
+            settings,
+            args.model.clone(),
+            args.enable_gpt4,
+        );
+        if args.config_info {
+            println!("{}", effective.describe());
+            exit(0);
+        }
+        if let Some(format) = args.print_config {
+            print_config(&effective, &args, format);
+            exit(0);
+        }
+        if let Some(dir) = &args.export_all {
+            let system = args.export_system || effective.export_system;
+            match session::export_all(dir, system, args.role_tag) {
+                Ok(count) => {
+                    println!("exported {count} sessions to {}", dir.display());
+                    exit(0);
+                }
+                Err(e) => {
+                    eprintln!("failed to export the sessions: {e}");
+                    exit(1);
+                }
+            }
+        }
+
+        let api_key = match effective.api_key {
+            Some(val) => {
                 // println!("api key: {val:?}");
-                val
+                val.value
             }
-            Err(_) => {
+            None => {
                 panic!("Set OPENAI_API_KEY as env var first please!");
             }
         };
 
-        let config = OpenAIConfig::new().with_api_key(api_key);
-        let client = Client::with_config(config);
-        let mut skin = MadSkin::default();
-        skin.set_fg(DarkCyan);
+        let mut config = OpenAIConfig::new()
+            .with_api_key(api_key)
+            .with_api_base(effective.api_base.value);
+        if let Some(org_id) = effective.org_id {
+            config = config.with_org_id(org_id.value);
+        }
+        let config = ApiConfig::new(config, effective.project_id.map(|p| p.value));
+        // the auth and organization headers are set per request, the user agent can't clash
+        let http = reqwest::Client::builder()
+            .user_agent(&effective.user_agent)
+            .build()
+            .unwrap_or_else(|e| {
+                eprintln!("invalid user agent {:?}: {e}", effective.user_agent);
+                exit(1);
+            });
+        let client = Client::with_config(config).with_http_client(http.clone());
+        let skin = render::skin(&effective.theme);
+
+        let pick_model = matches!(effective.model.source, settings::Source::Default);
+        let model = effective.model.value;
 
-        let mut model = "gpt-3.5-turbo";
-        let args = AppArgs::parse();
-        if args.enable_gpt4 {
-            model = "gpt-4-1106-preview";
+        let (pmt, batch) = match &args.stdin_prompt_separator {
+            Some(separator) => {
+                let separator = separator.replace("\\n", "\n");
+                let prompts = piped
+                    .split(separator.as_str())
+                    .filter(|pmt| !pmt.trim().is_empty())
+                    .map(|pmt| initial_prompt(&args.pmt, pmt))
+                    .collect();
+                (String::new(), Some((separator, prompts)))
+            }
+            None => (initial_prompt(&args.pmt, &piped), None),
+        };
+
+        let mut history = Vec::new();
+        let mut parent = None;
+        if args.resume {
+            let path = paths::session_file(&args.session);
+            match Session::load(&path) {
+                Ok(session) => {
+                    if session.is_newer() {
+                        warn_newer_session(&path, session.version);
+                    }
+                    history = session.to_history();
+                    parent = session.parent;
+                }
+                Err(e) => eprintln!("failed to load the session from {}: {e}", path.display()),
+            }
+        }
+        if let Some(path) = &args.continue_from {
+            let transcript = std::fs::read_to_string(path)
+                .map_err(|e| e.to_string())
+                .and_then(|text| session::parse_transcript(&text));
+            match transcript {
+                Ok(messages) => history.extend(messages.iter().filter_map(|m| m.to_message())),
+                Err(e) => {
+                    eprintln!("failed to load the transcript {}: {e}", path.display());
+                    exit(1);
+                }
+            }
+        }
+        if let Some(path) = &args.messages_file {
+            match session::load_messages(path) {
+                Ok(messages) => history.extend(messages),
+                Err(e) => {
+                    eprintln!("failed to load the messages: {e}");
+                    exit(1);
+                }
+            }
         }
 
-        let pmt = args.pmt.join(" ");
+        let tee = args.tee.as_ref().map(|path| {
+            std::fs::File::create(path).unwrap_or_else(|e| {
+                eprintln!("failed to create {}: {e}", path.display());
+                exit(1);
+            })
+        });
 
-        Self {
+        let personas_file = args.personas_file.unwrap_or_else(paths::personas_file);
+        let personas = persona::load_personas(&personas_file).unwrap_or_else(|e| {
+            eprintln!("failed to load the personas: {e}");
+            Personas::new()
+        });
+
+        let mut app = Self {
             client,
+            http,
             skin,
             model,
+            pick_model,
+            profile: args.profile,
+            reasoning_effort: args.reasoning_effort,
+            verbosity: args.verbosity,
+            tokens_field: args.tokens_field,
+            completion_tokens_models: BTreeSet::new(),
+            instruction_role: args.instruction_role,
+            temperature: args.temperature,
+            top_p: args.top_p,
+            max_tokens: args.max_tokens,
+            model_params: effective.models,
             initial_pmt: pmt,
-            history: Vec::new(),
+            history,
+            typeahead: String::new(),
+            trimmed: 0,
+            session: args.session,
+            parent,
+            tail: args.tail,
+            autosave: args.autosave_interval,
+            max_history_bytes: args.max_history_bytes,
+            turns_since_save: 0,
+            last_save: Instant::now(),
+            personas,
+            favorite_models: effective.favorite_models.value,
+            send_key: args.send_key.unwrap_or_else(SendKey::detect),
+            max_line: args.max_line.filter(|&max| max > 0),
+            prompt: effective.prompt,
+            greeting: effective.greeting,
+            waiting: effective.waiting,
+            aliases: effective.aliases,
+            alias_sigil: effective.alias_sigil,
+            prepend: args.prepend.clone().or(effective.prepend),
+            append: args.append.clone().or(effective.append),
+            export_system: args.export_system || effective.export_system,
+            role_tag: args.role_tag,
+            save_system: !args.no_save_system && effective.save_system,
+            serve_addr: args.serve,
+            batch,
+            stateless: args.stateless,
+            json: args.json,
+            list_models: args.list_models,
+            hyperlinks: !args.no_hyperlinks,
+            wrap: args.wrap,
+            table_fit: args.table_fit,
+            moderate: args.moderate,
+            diff: args.diff,
+            echo_prompt: args.echo_prompt,
+            show_reasoning: args.show_reasoning,
+            verbose: args.verbose,
+            stats: args.stats,
+            confirm_over: (!args.yes).then_some(args.confirm_over),
+            auto_continue: args.auto_continue.unwrap_or(0),
+            show_rate_limit: args.show_rate_limit,
+            pace: args.pace,
+            rate_limits: None,
+            typewriter: args
+                .typewriter
+                .filter(|&ms| ms > 0)
+                .map(Duration::from_millis),
+            flush_every: args.flush_every,
+            progressive: args.progressive && !args.no_reflow,
+            no_reflow: args.no_reflow,
+            strip_markdown: args.strip_markdown,
+            tui: args.tui,
+            suffix: args.suffix,
+            retry_empty: args.retry_empty,
+            tee,
+            copy: args.copy,
+            truncated: false,
+            continuing: None,
+            in_flight: Arc::default(),
+        };
+        if let Some(name) = args.persona {
+            if !app.switch_persona(&name) {
+                exit(1);
+            }
         }
+        if let Some(text) = args.developer {
+            // after the persona's prompt, if any
+            let message = ChatCompletionRequestSystemMessageArgs::default()
+                .content(text)
+                .build()
+                .unwrap();
+            let at = app
+                .history
+                .iter()
+                .take_while(|m| matches!(m, ChatCompletionRequestMessage::System(_)))
+                .count();
+            app.history.insert(at, message.into());
+        }
+        if !args.context.is_empty() {
+            // the files are sent with every request, they can't take the room of the answer
+            let room = context_window(&app.model)
+                .saturating_sub(app.params().max_tokens as usize)
+                .saturating_sub(app.history.iter().map(estimate_tokens).sum());
+            let budget = args.context_budget.min(room);
+            if budget < args.context_budget {
+                eprintln!(
+                    "--context-budget lowered to {budget} tokens, what {} leaves after the prompts and max_tokens",
+                    app.model
+                );
+            }
+            let files =
+                context::collect(&args.context, budget, !args.no_gitignore).unwrap_or_else(|e| {
+                    eprintln!("{e}");
+                    exit(1);
+                });
+            // after the instructions, ahead of the conversation
+            let message = ChatCompletionRequestSystemMessageArgs::default()
+                .content(format!("The files of the project:\n\n{files}"))
+                .build()
+                .unwrap();
+            let at = app
+                .history
+                .iter()
+                .take_while(|m| matches!(m, ChatCompletionRequestMessage::System(_)))
+                .count();
+            app.history.insert(at, message.into());
+        }
+        app
     }
 
-    // read user input from terminal
-    fn read_pmt() -> String {
+    // read user input from terminal, `prefill` is put in the editor before any key stroke
+    fn read_pmt(&self, prefill: String) -> String {
+        let send_key = self.send_key;
         // with raw mode enabled, we need to handle every aspect of stdout(eg: short-cut,
         // backspace, every key stroke, etc)
         let _ = enable_raw_mode();
+        // the model or the size of the terminal may have changed since the last prompt
+        tui::draw(&self.status_line());
+        // ask the terminal to report modified enter, for the ones not supporting it that's a no-op
+        let disambiguate = send_key == SendKey::CtrlEnter;
+        if disambiguate {
+            let _ = execute!(
+                stdout(),
+                PushKeyboardEnhancementFlags(KeyboardEnhancementFlags::DISAMBIGUATE_ESCAPE_CODES)
+            );
+        }
+        let leave = |disambiguate: bool| {
+            if disambiguate {
+                let _ = execute!(stdout(), PopKeyboardEnhancementFlags);
+            }
+            let _ = disable_raw_mode();
+        };
+        let mut last_was_enter = false;
 
         // let mut pmt = String::new();
         let mut pmts: Vec<String> = Vec::new();
         let mut cursor_index: usize = 0;
         let mut pmts_index: usize = 0;
         let mut stdout = stdout();
+        // the lines start after the prompt, eg: cursor_index 0 is on the column `col(0)`
+        let col = |index: usize| self.prompt.width() as u16 + index as u16;
+        execute!(stdout, style::Print(self.pmt_prefix(0))).unwrap();
+        if !prefill.is_empty() {
+            pmts = prefill.split('\n').map(String::from).collect();
+            pmts_index = pmts.len() - 1;
+            cursor_index = pmts[pmts_index].len();
+            let separator = format!("\r\n{}", self.pmt_prefix(1));
+            execute!(stdout, style::Print(pmts.join(&separator))).unwrap();
+        }
         loop {
-            if let Event::Key(key) = event::read().unwrap() {
+            let event = match event::read() {
+                Ok(event) => event,
+                // the terminal is gone (eg: closed stdin), there's no more input to wait for
+                Err(e) => {
+                    leave(disambiguate);
+                    tui::leave();
+                    eprintln!("\ninput closed: {e}");
+                    session::save_pending();
+                    exit(0);
+                }
+            };
+            if let Event::Key(key) = event {
+                // the terminals reporting the releases (eg: on windows) would type twice
+                if key.kind == KeyEventKind::Release {
+                    continue;
+                }
+                let was_enter =
+                    std::mem::replace(&mut last_was_enter, key.code == event::KeyCode::Enter);
                 match key.code {
                     event::KeyCode::Up => {
                         if pmts_index > 0 {
@@ -132,11 +1287,8 @@ impl App {
 
                             let current_line = pmts.get(pmts_index).unwrap();
                             if cursor_index > current_line.len() {
-                                execute!(
-                                    stdout,
-                                    cursor::MoveToColumn(1 + current_line.len() as u16)
-                                )
-                                .unwrap();
+                                execute!(stdout, cursor::MoveToColumn(col(current_line.len())))
+                                    .unwrap();
                                 cursor_index = current_line.len();
                             }
                         }
@@ -149,12 +1301,48 @@ impl App {
 
                             let current_line = pmts.get(pmts_index).unwrap();
                             if cursor_index > current_line.len() - 1 {
-                                execute!(
-                                    stdout,
-                                    cursor::MoveToColumn(1 + current_line.len() as u16)
-                                )
-                                .unwrap();
+                                execute!(stdout, cursor::MoveToColumn(col(current_line.len())))
+                                    .unwrap();
+                                cursor_index = current_line.len();
+                            }
+                        }
+                    }
+
+                    // same as ctrl-a/ctrl-e
+                    event::KeyCode::Home => {
+                        execute!(stdout, cursor::MoveToColumn(col(0))).unwrap();
+                        cursor_index = 0;
+                    }
+
+                    event::KeyCode::End => {
+                        if let Some(current_line) = pmts.get(pmts_index) {
+                            cursor_index = current_line.trim_end_matches('\n').len();
+                            execute!(stdout, cursor::MoveToColumn(col(cursor_index))).unwrap();
+                        }
+                    }
+
+                    // jump to the first/last line of the prompt
+                    event::KeyCode::PageUp => {
+                        if pmts_index > 0 {
+                            execute!(stdout, cursor::MoveUp(pmts_index as u16)).unwrap();
+                            pmts_index = 0;
+                            let current_line = pmts.get(pmts_index).unwrap();
+                            if cursor_index > current_line.len() {
+                                cursor_index = current_line.len();
+                                execute!(stdout, cursor::MoveToColumn(col(cursor_index))).unwrap();
+                            }
+                        }
+                    }
+
+                    event::KeyCode::PageDown => {
+                        if pmts_index + 1 < pmts.len() {
+                            let last = pmts.len() - 1;
+                            execute!(stdout, cursor::MoveDown((last - pmts_index) as u16)).unwrap();
+                            pmts_index = last;
+                            let current_line = pmts.get(pmts_index).unwrap();
+                            if cursor_index > current_line.len() {
                                 cursor_index = current_line.len();
+                                execute!(stdout, cursor::MoveToColumn(col(cursor_index))).unwrap();
                             }
                         }
                     }
@@ -180,9 +1368,28 @@ impl App {
                     }
 
                     event::KeyCode::Enter => {
-                        //ctrl-Enter for sending pmt
-                        if key.modifiers.contains(event::KeyModifiers::CONTROL) {
-                            let _ = disable_raw_mode();
+                        // alt-enter (or shift-enter, for the terminals reporting it) always
+                        // inserts a newline, whatever the send key is
+                        let newline_only = key
+                            .modifiers
+                            .intersects(event::KeyModifiers::ALT | event::KeyModifiers::SHIFT);
+                        let send = !newline_only
+                            && match send_key {
+                                SendKey::CtrlEnter => {
+                                    key.modifiers.contains(event::KeyModifiers::CONTROL)
+                                }
+                                SendKey::Enter => true,
+                                // the first enter already opened an empty line
+                                SendKey::DoubleEnter => {
+                                    was_enter && pmts.get(pmts_index).is_none_or(String::is_empty)
+                                }
+                                SendKey::CtrlD => false,
+                            };
+                        if send {
+                            if send_key == SendKey::DoubleEnter && pmts_index < pmts.len() {
+                                pmts.remove(pmts_index);
+                            }
+                            leave(disambiguate);
                             return pmts.join("\n");
                         }
 
@@ -190,7 +1397,7 @@ impl App {
                         if let Some(current_line) = pmts.get_mut(pmts_index - 1) {
                             let new_line = current_line.drain(cursor_index..).collect();
                             pmts.insert(pmts_index, new_line);
-                            App::rerender_pmts(&mut stdout, pmts.clone(), pmts_index - 1);
+                            self.rerender_pmts(&mut stdout, pmts.clone(), pmts_index - 1);
                             execute!(stdout, cursor::MoveDown(1)).unwrap();
                         } else {
                             if pmts_index > pmts.len() {
@@ -199,9 +1406,19 @@ impl App {
                                 pmts.insert(pmts_index, "".to_string());
                             }
                             execute!(stdout, style::Print("\n")).unwrap();
+                            execute!(stdout, cursor::MoveToColumn(0)).unwrap();
+                            execute!(stdout, style::Print(self.pmt_prefix(pmts_index))).unwrap();
                         }
 
-                        execute!(stdout, cursor::MoveToColumn(1)).unwrap();
+                        execute!(stdout, cursor::MoveToColumn(col(0))).unwrap();
+                        cursor_index = 0;
+                    }
+
+                    // scrap the input and start over, unlike ctrl-c it never quits
+                    event::KeyCode::Esc => {
+                        self.clear_pmts(&mut stdout, pmts_index);
+                        pmts.clear();
+                        pmts_index = 0;
                         cursor_index = 0;
                     }
 
@@ -210,21 +1427,43 @@ impl App {
                         if key.modifiers.contains(event::KeyModifiers::CONTROL) && c == 'c' {
                             if pmts.is_empty() {
                                 // execute!(stdout, style::Print("\nBye!"));
-                                let _ = disable_raw_mode();
+                                leave(disambiguate);
+                                tui::leave();
+                                // the turns the auto-save didn't write yet
+                                session::save_pending();
                                 println!("\nBye!");
                                 exit(0);
                             } else {
-                                execute!(stdout, cursor::MoveToColumn(1)).unwrap();
-                                execute!(stdout, cursor::MoveUp(pmts.len() as u16 - 1)).unwrap();
-                                execute!(stdout, Clear(ClearType::FromCursorDown)).unwrap();
-                                execute!(stdout, cursor::MoveToColumn(1)).unwrap();
-
+                                self.clear_pmts(&mut stdout, pmts_index);
                                 pmts.clear();
                                 pmts_index = 0;
                                 cursor_index = 0;
                                 continue;
                             }
                         }
+                        if key.modifiers.contains(event::KeyModifiers::CONTROL)
+                            && c == 'd'
+                            && send_key == SendKey::CtrlD
+                        {
+                            leave(disambiguate);
+                            return pmts.join("\n");
+                        }
+                        // clear the screen like the shells do, the input is drawn again on top
+                        // with the cursor where it was in it
+                        if key.modifiers.contains(event::KeyModifiers::CONTROL) && c == 'l' {
+                            execute!(stdout, Clear(ClearType::All), cursor::MoveTo(0, 0)).unwrap();
+                            tui::draw(&self.status_line());
+                            let separator = format!("\r\n{}", self.pmt_prefix(1));
+                            let input = self.pmt_prefix(0) + &pmts.join(&separator);
+                            execute!(stdout, style::Print(input)).unwrap();
+                            execute!(
+                                stdout,
+                                cursor::MoveTo(0, pmts_index as u16),
+                                cursor::MoveToColumn(col(cursor_index))
+                            )
+                            .unwrap();
+                            continue;
+                        }
                         if key.modifiers.contains(event::KeyModifiers::CONTROL) && c == 'e' {
                             if let Some(current_line) = pmts.get(pmts_index) {
                                 let mut cln = current_line.len();
@@ -232,26 +1471,48 @@ impl App {
                                     cln -= 1;
                                 }
                                 cursor_index = cln;
-                                execute!(stdout, cursor::MoveToColumn(cursor_index as u16 + 1))
-                                    .unwrap();
+                                execute!(stdout, cursor::MoveToColumn(col(cursor_index))).unwrap();
                             }
                             continue;
                         }
                         if key.modifiers.contains(event::KeyModifiers::CONTROL) && c == 'a' {
-                            execute!(stdout, cursor::MoveToColumn(1)).unwrap();
+                            execute!(stdout, cursor::MoveToColumn(col(0))).unwrap();
                             cursor_index = 0;
                             continue;
                         }
 
                         if let Some(current_line) = pmts.get_mut(pmts_index) {
                             current_line.insert(cursor_index, c);
-                            App::rerender_pmts(&mut stdout, pmts.clone(), pmts_index);
+                            self.rerender_pmts(&mut stdout, pmts.clone(), pmts_index);
                             execute!(stdout, cursor::MoveRight(1_u16)).unwrap();
                         } else {
                             pmts.insert(pmts_index, c.to_string());
                             execute!(stdout, style::Print(c)).unwrap();
                         }
                         cursor_index += 1;
+                        // a line typed (or pasted) past --max-line is broken at its last space,
+                        // as if enter was pressed there
+                        let line = &pmts[pmts_index];
+                        if self.max_line.is_some_and(|max| line.chars().count() > max)
+                            && cursor_index == line.len()
+                        {
+                            let at = line
+                                .rfind(' ')
+                                .filter(|&at| at > 0)
+                                .unwrap_or(line.len() - c.len_utf8());
+                            let tail = pmts[pmts_index].split_off(at);
+                            let tail = tail.strip_prefix(' ').unwrap_or(&tail).to_string();
+                            cursor_index = tail.len();
+                            pmts.insert(pmts_index + 1, tail);
+                            self.rerender_pmts(&mut stdout, pmts.clone(), pmts_index);
+                            pmts_index += 1;
+                            execute!(
+                                stdout,
+                                cursor::MoveDown(1),
+                                cursor::MoveToColumn(col(cursor_index))
+                            )
+                            .unwrap();
+                        }
                     }
                     event::KeyCode::Backspace | event::KeyCode::Delete => {
                         if cursor_index > 0 {
@@ -263,27 +1524,35 @@ impl App {
                                 execute!(stdout, cursor::MoveLeft(1_u16)).unwrap();
                             } else {
                                 execute!(stdout, Clear(ClearType::CurrentLine)).unwrap();
-                                execute!(stdout, cursor::MoveToColumn(1)).unwrap();
+                                execute!(stdout, cursor::MoveToColumn(0)).unwrap();
+                                execute!(stdout, style::Print(self.pmt_prefix(pmts_index)))
+                                    .unwrap();
                             }
                         }
                     }
-                    _ => break,
+                    // only the send key sends, the keys without a binding (eg: tab, the
+                    // f-keys) are ignored
+                    _ => {}
                 }
             }
             let _ = stdout.flush();
         }
-        let _ = disable_raw_mode();
-        pmts.join("\n")
     }
 
-    fn rerender_pmts(stdout: &mut std::io::Stdout, mut pmts: Vec<String>, current_row: usize) {
+    fn rerender_pmts(
+        &self,
+        stdout: &mut std::io::Stdout,
+        mut pmts: Vec<String>,
+        current_row: usize,
+    ) {
         execute!(stdout, cursor::SavePosition).unwrap();
-        execute!(stdout, cursor::MoveToColumn(1_u16)).unwrap();
+        execute!(stdout, cursor::MoveToColumn(0)).unwrap();
         execute!(stdout, Clear(ClearType::FromCursorDown)).unwrap();
 
         let mut tmp = 0;
         pmts.drain(current_row..).for_each(|mut line| {
-            execute!(stdout, cursor::MoveToColumn(1_u16)).unwrap();
+            execute!(stdout, cursor::MoveToColumn(0)).unwrap();
+            line = self.pmt_prefix(current_row + tmp) + &line;
             if tmp != 0 {
                 line = "\n".to_string() + &line;
             }
@@ -293,10 +1562,204 @@ impl App {
         execute!(stdout, cursor::RestorePosition).unwrap();
     }
 
-    async fn send_message(
-        &mut self,
-        pmt: String,
-    ) -> Result<ChatCompletionResponseStream, OpenAIError> {
+    // erase the input, the cursor being on its line `current_row`, and draw the prompt again
+    fn clear_pmts(&self, stdout: &mut std::io::Stdout, current_row: usize) {
+        execute!(stdout, cursor::MoveToColumn(0)).unwrap();
+        // crossterm moves by a row when asked to move by none
+        if current_row > 0 {
+            execute!(stdout, cursor::MoveUp(current_row as u16)).unwrap();
+        }
+        execute!(stdout, Clear(ClearType::FromCursorDown)).unwrap();
+        execute!(stdout, cursor::MoveToColumn(0)).unwrap();
+        execute!(stdout, style::Print(self.pmt_prefix(0))).unwrap();
+    }
+
+    // an api error in short, in full with --verbose
+    fn print_api_error(&self, context: &str, e: &OpenAIError) {
+        let detail = self.verbose.then(|| format!("{e:#?}"));
+        print_error(
+            &format!("{context}: {}", api::describe_error(e)),
+            detail.as_deref(),
+        );
+    }
+
+    // the line kept under the conversation with --tui
+    fn status_line(&self) -> String {
+        format!(
+            " {} · session {} · {} messages",
+            self.model,
+            self.session,
+            self.history.len()
+        )
+    }
+
+    // what's drawn before an input line, the prompt for the first one and as much indentation
+    // for the next ones
+    fn pmt_prefix(&self, row: usize) -> String {
+        if row == 0 {
+            self.skin.bold.apply_to(&self.prompt).to_string()
+        } else {
+            " ".repeat(self.prompt.width())
+        }
+    }
+
+    // list the models of the api, the compatible providers included
+    async fn print_models(&self, filter: ModelFilter) {
+        let models = match self.client.models().list().await {
+            Ok(models) => models,
+            Err(e) => {
+                self.print_api_error("failed to list the models", &e);
+                exit(1);
+            }
+        };
+        let mut ids: Vec<&str> = models
+            .data
+            .iter()
+            .map(|model| model.id.as_str())
+            .filter(|id| matches!(filter, ModelFilter::All) || is_chat_model(id))
+            .collect();
+        ids.sort();
+        let mut md = String::new();
+        for id in ids {
+            md.push_str(&format!("* `{id}`\n"));
+        }
+        if md.is_empty() {
+            md.push_str("*no models found*\n");
+        }
+        print!("{}", self.skin.term_text(&md));
+    }
+
+    // let a new user choose the model, rather than quietly going with the default one
+    async fn pick_model(&mut self) {
+        let mut models: Vec<String> = match self.client.models().list().await {
+            Ok(models) => models
+                .data
+                .into_iter()
+                .map(|model| model.id)
+                .filter(|id| is_chat_model(id))
+                .collect(),
+            // eg: no api key yet
+            Err(_) => self.favorite_models.clone(),
+        };
+        models.sort();
+        if !models.contains(&self.model) {
+            models.insert(0, self.model.clone());
+        }
+        let current = models.iter().position(|m| *m == self.model).unwrap_or(0);
+        let title = format!(
+            "No model configured, pick one (↑/↓ and enter, esc for {}):",
+            self.model
+        );
+        let Some(i) = picker::pick(&title, &models, current) else {
+            return;
+        };
+        self.model = models.swap_remove(i);
+        eprintln!("{}", self.skin.inline(&format!("*model: {}*", self.model)));
+        if confirm("Remember this choice?") {
+            match settings::save_model(&self.profile, &self.model) {
+                Ok(()) => eprintln!("saved to {}", paths::config_file().display()),
+                Err(e) => eprintln!("failed to save the model: {e}"),
+            }
+        }
+    }
+
+    // save the session once the auto-save interval has elapsed, called after every turn
+    fn autosave(&mut self) {
+        self.turns_since_save += 1;
+        let due = match self.autosave {
+            Some(Autosave::Turns(turns)) => self.turns_since_save >= turns,
+            Some(Autosave::Every(interval)) => self.last_save.elapsed() >= interval,
+            None => false,
+        };
+        if due {
+            self.save_session();
+        } else if self.autosave.is_some() {
+            // not lost when the process is terminated before the next save
+            let mut session = self.session_to_save();
+            session.trim_to(self.max_history_bytes);
+            session::set_pending(paths::session_file(&self.session), session);
+        }
+    }
+
+    // the session as it's saved, the current run keeps the whole history
+    fn session_to_save(&self) -> Session {
+        let mut session = Session::from_history(&self.history);
+        session.parent = self.parent.clone();
+        if !self.save_system {
+            // only the system prompt opening the history, not the rest of the conversation
+            session.messages.drain(..self.pinned());
+        }
+        session
+    }
+
+    fn save_session(&mut self) {
+        let path = paths::session_file(&self.session);
+        session::set_pending_saved();
+        // only the file is trimmed
+        let mut session = self.session_to_save();
+        let dropped = session.trim_to(self.max_history_bytes);
+        if dropped > 0 {
+            eprintln!(
+                "{}",
+                self.skin.inline(&format!(
+                    "*⚠ the session is over {} bytes, {dropped} oldest messages were left out of the saved file*",
+                    self.max_history_bytes
+                ))
+            );
+        }
+        if let Err(e) = session.save(&path) {
+            eprintln!("failed to save the session to {}: {e}", path.display());
+        }
+        self.turns_since_save = 0;
+        self.last_save = Instant::now();
+    }
+
+    // number of system messages opening the history: the persona, the context files. they're
+    // sent with every request, only the turns after them are trimmed to fit the context
+    fn pinned(&self) -> usize {
+        self.history
+            .iter()
+            .take_while(|m| matches!(m, ChatCompletionRequestMessage::System(_)))
+            .count()
+    }
+
+    // whether the history message `i` was left out of the last request
+    fn is_trimmed(&self, i: usize) -> bool {
+        i >= self.pinned() && i < self.trimmed
+    }
+
+    // the messages sent with a request: the pinned ones and the turns that fit
+    fn window(&self) -> Vec<ChatCompletionRequestMessage> {
+        let pinned = self.pinned();
+        let mut messages = self.history[..pinned].to_vec();
+        messages.extend_from_slice(&self.history[self.trimmed.max(pinned)..]);
+        messages
+    }
+
+    // index of the oldest turn after the pinned system messages that still fits in the model's
+    // context window, keeping at least the latest message
+    fn context_window_start(&self) -> usize {
+        let pinned = self.pinned();
+        let budget = context_window(&self.model)
+            .saturating_sub(self.params().max_tokens as usize)
+            .saturating_sub(self.history[..pinned].iter().map(estimate_tokens).sum());
+        let mut used = 0;
+        let mut start = self.history.len();
+        while start > pinned {
+            used += estimate_tokens(&self.history[start - 1]);
+            if used > budget && start < self.history.len() {
+                break;
+            }
+            start -= 1;
+        }
+        start
+    }
+
+    async fn send_message(&mut self, pmt: String) -> Result<ChatStream, OpenAIError> {
+        let pmt = self.wrap_prompt(pmt);
+        if self.moderate {
+            self.check_moderation(&pmt).await?;
+        }
         let message = ChatCompletionRequestUserMessageArgs::default()
             .content(pmt)
             .build()
@@ -304,19 +1767,366 @@ impl App {
             .into();
 
         self.history.push(message);
-        let request = CreateChatCompletionRequestArgs::default()
-            .model(self.model)
-            .max_tokens(1234_u16)
-            .messages(self.history.to_vec())
-            .build()
-            .unwrap();
+        self.trimmed = self.context_window_start();
+        let dropped = self.trimmed - self.pinned();
+        if dropped > 0 {
+            let note = format!("⚠ dropped {dropped} earlier messages to fit context");
+            println!("{}", style::Stylize::dim(note));
+        }
+        if let Err(e) = self.confirm_cost() {
+            self.history.pop();
+            return Err(OpenAIError::InvalidArgument(e));
+        }
+        let body = self.request_body(self.window());
+        match self.request(body).await {
+            Ok(stream) => Ok(stream),
+            Err(e) => {
+                // the prompt didn't make it, it's not part of the conversation
+                self.history.pop();
+                Err(e)
+            }
+        }
+    }
+
+    // the prompt between the `--prepend` and `--append` texts
+    fn wrap_prompt(&self, pmt: String) -> String {
+        let mut parts = vec![];
+        parts.extend(self.prepend.as_deref());
+        parts.push(pmt.trim_end());
+        parts.extend(self.append.as_deref());
+        parts.join("\n\n")
+    }
+
+    // a prompt of the history without what `wrap_prompt` put around it
+    fn unwrap_prompt<'a>(&self, pmt: &'a str) -> &'a str {
+        let pmt = match &self.prepend {
+            Some(prepend) => pmt
+                .strip_prefix(prepend.as_str())
+                .map_or(pmt, |pmt| pmt.trim_start_matches('\n')),
+            None => pmt,
+        };
+        match &self.append {
+            Some(append) => pmt
+                .strip_suffix(append.as_str())
+                .map_or(pmt, |pmt| pmt.trim_end_matches('\n')),
+            None => pmt,
+        }
+    }
+
+    // refuse a prompt flagged by the moderation endpoint, telling the categories it tripped
+    async fn check_moderation(&self, pmt: &str) -> Result<(), OpenAIError> {
+        let request = CreateModerationRequestArgs::default().input(pmt).build()?;
+        let resp = self
+            .client
+            .moderations()
+            .create(request)
+            .await
+            .map_err(|e| {
+                OpenAIError::InvalidArgument(format!(
+                    "failed to moderate the prompt, it wasn't sent: {}",
+                    api::describe_error(&e)
+                ))
+            })?;
+        let flagged: Vec<_> = resp.results.iter().filter(|r| r.flagged).collect();
+        if flagged.is_empty() {
+            eprintln!("{}", self.skin.inline("*moderation: passed*"));
+            return Ok(());
+        }
+        // the categories are a struct of booleans
+        let mut categories: Vec<String> = flagged
+            .iter()
+            .filter_map(|r| serde_json::to_value(&r.categories).ok())
+            .filter_map(|categories| categories.as_object().cloned())
+            .flat_map(|categories| categories.into_iter())
+            .filter(|(_, tripped)| tripped.as_bool() == Some(true))
+            .map(|(category, _)| category)
+            .collect();
+        categories.sort();
+        categories.dedup();
+        Err(OpenAIError::InvalidArgument(format!(
+            "the prompt was flagged by the moderation ({}), it wasn't sent",
+            categories.join(", ")
+        )))
+    }
+
+    // resume a response cut off by a dropped connection, the model is handed what it already
+    // answered and asked to carry on from there
+    // the stream it replaces must be dropped first, the guard goes over to the resumed one
+    async fn continue_resp(&mut self, partial: &str) -> ChatStream {
+        let in_flight = InFlight::begin(&self.in_flight);
+        match self.create_stream(self.continue_body(partial)).await {
+            Ok(stream) => match in_flight {
+                Some(in_flight) => in_flight.hold(stream),
+                None => stream,
+            },
+            // handed to the streaming loop like any error of the stream
+            Err(e) => Box::pin(futures::stream::once(async move { Err(e) })),
+        }
+    }
+
+    // the request for the rest of the `partial` answer to the history's last prompt
+    fn continue_body(&self, partial: &str) -> serde_json::Value {
+        let mut messages = self.window();
+        messages.push(
+            ChatCompletionRequestAssistantMessageArgs::default()
+                .content(partial)
+                .build()
+                .unwrap()
+                .into(),
+        );
+        messages.push(
+            ChatCompletionRequestUserMessageArgs::default()
+                .content(
+                    "Your answer was cut off. Continue it exactly where it stopped, without \
+                     repeating anything.",
+                )
+                .build()
+                .unwrap()
+                .into(),
+        );
+        self.request_body(messages)
+    }
+
+    // tell about the flags the current model has no use for, they're not sent
+    fn notice_ignored_params(&self) {
+        let ignored = [
+            (
+                "--reasoning-effort",
+                self.reasoning_effort.is_some() && !takes_reasoning_effort(&self.model),
+            ),
+            (
+                "--verbosity",
+                self.verbosity.is_some() && !takes_verbosity(&self.model),
+            ),
+        ];
+        for (flag, _) in ignored.iter().filter(|(_, ignored)| *ignored) {
+            let notice = format!("({flag} is ignored by {})", self.model);
+            eprintln!("{}", style::Stylize::dim(notice));
+        }
+    }
+
+    // the field the token limit goes in for the current model
+    fn tokens_field(&self) -> TokensField {
+        match self.tokens_field {
+            Some(field) => field,
+            None if rejects_max_tokens(&self.model)
+                || self.completion_tokens_models.contains(&self.model) =>
+            {
+                TokensField::MaxCompletionTokens
+            }
+            None => TokensField::MaxTokens,
+        }
+    }
+
+    // send a streamed chat request, warning when the rate limits run low (and with --pace,
+    // waiting for them to reset when used up). a model rejecting max_tokens is sent
+    // max_completion_tokens instead, from then on
+    async fn create_stream(
+        &mut self,
+        mut body: serde_json::Value,
+    ) -> Result<ChatStream, OpenAIError> {
+        if let Some((limits, at)) = self.rate_limits.filter(|_| self.pace) {
+            let tokens: usize = self.window().iter().map(estimate_tokens).sum();
+            if let Some(wait) = limits.wait(tokens as u64, at.elapsed()) {
+                let note = format!("rate limits used up, waiting {:.1}s", wait.as_secs_f64());
+                // may be in raw mode, when resuming a response
+                eprint!("{}\r\n", style::Stylize::dim(note));
+                tokio::time::sleep(wait).await;
+            }
+        }
+        let result = api::create_stream_raw(self.client.config(), &self.http, body.clone()).await;
+        let (stream, limits) = match result {
+            Err(e)
+                if self.tokens_field.is_none()
+                    && body.get("max_tokens").is_some()
+                    && api::describe_error(&e).contains("max_completion_tokens") =>
+            {
+                self.completion_tokens_models.insert(self.model.clone());
+                if let Some(max_tokens) = body.as_object_mut().and_then(|b| b.remove("max_tokens"))
+                {
+                    body["max_completion_tokens"] = max_tokens;
+                }
+                api::create_stream_raw(self.client.config(), &self.http, body).await?
+            }
+            result => result?,
+        };
+        self.rate_limits = Some((limits, Instant::now()));
+        let note = match self.show_rate_limit {
+            true => limits.describe(),
+            false => limits.warning(),
+        };
+        if let Some(note) = note {
+            // may be in raw mode, when resuming a response
+            eprint!("{}\r\n", style::Stylize::dim(note));
+        }
+        Ok(stream)
+    }
+
+    // `create_stream`, unless a request is already in flight. the stream holds on to the
+    // `InFlight` guard, the next request can go once it's dropped
+    async fn request(&mut self, body: serde_json::Value) -> Result<ChatStream, OpenAIError> {
+        let Some(in_flight) = InFlight::begin(&self.in_flight) else {
+            return Err(OpenAIError::InvalidArgument(
+                "a request is already in flight, this one wasn't sent".to_string(),
+            ));
+        };
+        let stream = self.cancellable_request(body).await?;
+        Ok(in_flight.hold(stream))
+    }
+
+    // the terminal events, while a request is sent and its response streamed. none when piped
+    // in or serving: there are no keys to listen to, and crossterm has no terminal to read
+    fn key_events(&self) -> Pin<Box<dyn Stream<Item = std::io::Result<Event>> + Send>> {
+        if !std::io::stdin().is_terminal() || self.serve_addr.is_some() {
+            return Box::pin(futures::stream::pending());
+        }
+        Box::pin(EventStream::new())
+    }
+
+    // `create_stream`, given up when ctrl-c is pressed before the response starts. the terminal
+    // is in raw mode meanwhile, as while streaming, for ctrl-c to be a key rather than the
+    // signal killing the process. what's typed ahead is kept for the next prompt
+    async fn cancellable_request(
+        &mut self,
+        body: serde_json::Value,
+    ) -> Result<ChatStream, OpenAIError> {
+        // piped in, or serving, there are no keys to listen to
+        if !std::io::stdin().is_terminal() || self.serve_addr.is_some() {
+            return self.create_stream(body).await;
+        }
+        let mut events = self.key_events();
+        let mut typeahead = String::new();
+        let _ = enable_raw_mode();
+        let result = {
+            let request = self.create_stream(body);
+            tokio::pin!(request);
+            loop {
+                tokio::select! {
+                    result = &mut request => break result,
+                    Some(Ok(event::Event::Key(key))) = events.next() => {
+                        if key.modifiers.contains(event::KeyModifiers::CONTROL)
+                            && key.code == event::KeyCode::Char('c')
+                        {
+                            break Err(OpenAIError::InvalidArgument(
+                                "the request was cancelled".to_string(),
+                            ));
+                        }
+                        Self::buffer_typeahead(&mut typeahead, key);
+                    }
+                }
+            }
+        };
+        let _ = disable_raw_mode();
+        self.typeahead.push_str(&typeahead);
+        result
+    }
+
+    // ask before sending a request over the token threshold, Ok when it can go. with no one to
+    // ask (serving, stdin piped) it's refused rather than waiting on an answer
+    fn confirm_cost(&self) -> Result<(), String> {
+        let Some(threshold) = self.confirm_over else {
+            return Ok(());
+        };
+        let tokens: usize = self.window().iter().map(estimate_tokens).sum();
+        if tokens <= threshold {
+            return Ok(());
+        }
+        let cost = match input_price(&self.model) {
+            Some(price) => format!(" (~${:.2})", tokens as f64 * price / 1_000_000.0),
+            None => String::new(),
+        };
+        if !std::io::stdin().is_terminal() || self.serve_addr.is_some() {
+            return Err(format!(
+                "~{tokens} tokens{cost} is over --confirm-over {threshold}, pass --yes to send it"
+            ));
+        }
+        if !confirm(&format!("This will send ~{tokens} tokens{cost}. Continue?")) {
+            return Err("the request was cancelled".to_string());
+        }
+        Ok(())
+    }
+
+    // the parameters of the current model, its defaults from the config overridden by the flags
+    fn params(&self) -> Params {
+        let defaults = self
+            .model_params
+            .get(&self.model)
+            .cloned()
+            .unwrap_or_default();
+        Params {
+            temperature: self.temperature.or(defaults.temperature),
+            top_p: self.top_p.or(defaults.top_p),
+            max_tokens: self
+                .max_tokens
+                .or(defaults.max_tokens)
+                .unwrap_or(MAX_TOKENS),
+            reasoning_effort: self.reasoning_effort.or(defaults.reasoning_effort),
+            system_prompt: defaults.system_prompt,
+        }
+    }
+
+    // the streamed chat request sending `messages` to the current model
+    fn request_body(&self, mut messages: Vec<ChatCompletionRequestMessage>) -> serde_json::Value {
+        let params = self.params();
+        if let Some(prompt) = params.system_prompt {
+            if !messages
+                .iter()
+                .any(|m| matches!(m, ChatCompletionRequestMessage::System(_)))
+            {
+                let system = ChatCompletionRequestSystemMessageArgs::default()
+                    .content(prompt)
+                    .build()
+                    .unwrap();
+                messages.insert(0, system.into());
+            }
+        }
+        let mut args = CreateChatCompletionRequestArgs::default();
+        args.model(&self.model).messages(messages);
+        if let Some(temperature) = params.temperature {
+            args.temperature(temperature);
+        }
+        if let Some(top_p) = params.top_p {
+            args.top_p(top_p);
+        }
+        let tokens_field = self.tokens_field();
+        if tokens_field == TokensField::MaxTokens {
+            args.max_tokens(params.max_tokens);
+        }
+        let request = args.build().unwrap();
         // println!("request: {:#?}", request);
 
-        self.client.chat().create_stream(request).await
+        // the newer models reject max_tokens, the reasoning ones take parameters the sdk doesn't
+        // know about
+        let mut body = serde_json::to_value(&request).unwrap();
+        if tokens_field == TokensField::MaxCompletionTokens {
+            body["max_completion_tokens"] = params.max_tokens.into();
+        }
+        if takes_reasoning_effort(&self.model) {
+            if let Some(effort) = params.reasoning_effort {
+                body["reasoning_effort"] = effort.as_str().into();
+            }
+        }
+        if takes_verbosity(&self.model) {
+            if let Some(verbosity) = self.verbosity {
+                body["verbosity"] = verbosity.as_str().into();
+            }
+        }
+        // the instructions are kept as system messages, the sdk has no developer role yet
+        let role = self
+            .instruction_role
+            .unwrap_or_else(|| InstructionRole::for_model(&self.model));
+        if role == InstructionRole::Developer {
+            for message in body["messages"].as_array_mut().into_iter().flatten() {
+                if message["role"] == "system" {
+                    message["role"] = role.as_str().into();
+                }
+            }
+        }
+        body
     }
 
     //read response from the stream and print it as markdown
-    async fn streaming_and_rendering_resp(&mut self, mut stream: ChatCompletionResponseStream) {
+    async fn streaming_and_rendering_resp(&mut self, mut stream: ChatStream) -> StreamEnd {
         // From Rust docs on print: https://doc.rust-lang.org/std/macro.print.html
         //
         //  Note that stdout is frequently line-buffered by default so it may be necessary
@@ -326,53 +2136,749 @@ impl App {
         //  If you call print! within a hot loop, this behavior may be the bottleneck of the loop.
         //  To avoid this, lock stdout with io::stdout().lock():
         let mut lock = stdout().lock();
-        let mut resp_buf = "".to_string();
-        while let Some(result) = stream.next().await {
-            match result {
-                Ok(resp) => resp.choices.iter().for_each(|chat_choice| {
-                    if let Some(ref content) = chat_choice.delta.content {
-                        write!(lock, "{content}").unwrap();
-                        resp_buf.push_str(content.as_ref());
+        let mut streamed = Streamed {
+            wrap: self
+                .wrap
+                .then(|| StreamWrap::new(size().map_or(80, |(width, _)| width))),
+            light: self.no_reflow.then(LightStyle::default),
+            ..Default::default()
+        };
+        // keep listening to the keyboard while streaming, so ctrl-c can cancel the response
+        // and whatever is typed ahead ends up in the next prompt instead of being lost
+        let mut events = self.key_events();
+        let mut cancelled = false;
+        let mut think = ThinkSplitter::default();
+        let mut continued = 0; // times the response was resumed after the connection dropped
+        let mut resume = false;
+        let mut done = false;
+        // with --typewriter the content is queued here and typed out a char per tick
+        let mut paced = VecDeque::new();
+        let mut tick = tokio::time::interval(self.typewriter.unwrap_or(Duration::from_millis(1)));
+        let mut flusher = Flusher::new(self.flush_every);
+        let _ = enable_raw_mode();
+        if !self.waiting.is_empty() {
+            // styled by the skin, so it follows the theme of the rest of the output
+            write!(lock, "{}", self.skin.inline(&self.waiting)).unwrap();
+            lock.flush().unwrap();
+            streamed.waiting = true;
+        }
+        loop {
+            if done && paced.is_empty() {
+                break;
+            }
+            if std::mem::take(&mut resume) {
+                // letting go of the in-flight guard of the dropped connection
+                stream = Box::pin(futures::stream::empty());
+                stream = self.continue_resp(&streamed.answer).await;
+            }
+            tokio::select! {
+                result = stream.next(), if !done => {
+                    let Some(result) = result else {
+                        done = true;
+                        continue;
+                    };
+                    // what's queued goes out at once, ahead of the error or the resumed answer
+                    if result.is_err() {
+                        let queued: String = paced.drain(..).collect();
+                        self.write_delta(&mut lock, "", &queued, &mut streamed);
                     }
-                }),
-                Err(e) => {
-                    writeln!(lock, "error: {:#?}", e).unwrap();
+                    match result {
+                        // raw mode doesn't turn "\n" into "\r\n" for us
+                        Ok(resp) => {
+                            for chat_choice in resp.choices.iter() {
+                                // the deltas are whole strings, the event stream holds back a
+                                // multibyte char cut between two network chunks
+                                let (tagged, content) = match &chat_choice.delta.content {
+                                    Some(content) => think.push(content),
+                                    None => Default::default(),
+                                };
+                                if chat_choice.finish_reason.as_deref() == Some("length") {
+                                    streamed.truncated = true;
+                                }
+                                let thought = chat_choice.delta.reasoning().unwrap_or_default();
+                                let thought = thought.to_string() + &tagged;
+                                if self.typewriter.is_some() {
+                                    self.write_delta(&mut lock, &thought, "", &mut streamed);
+                                    paced.extend(content.chars());
+                                } else {
+                                    self.write_delta(&mut lock, &thought, &content, &mut streamed);
+                                }
+                            }
+                        }
+                        // the stream ends on a dropped connection, what was answered so far
+                        // is handed back to the model for it to carry on
+                        Err(OpenAIError::StreamError(e))
+                            if continued < self.auto_continue && !streamed.answer.is_empty() =>
+                        {
+                            continued += 1;
+                            let note = format!(
+                                "[connection lost ({e}), continuing {continued}/{}]",
+                                self.auto_continue
+                            );
+                            let note = style::Stylize::dim(note);
+                            streamed.write_note(&mut lock, &format!("\n{note}\n"));
+                            resume = true;
+                        }
+                        Err(e) => {
+                            let error = api::describe_error(&e);
+                            let detail = format!("{e:#?}");
+                            let note = match self.verbose {
+                                true => format!("error: {error}\n{detail}\n"),
+                                false => format!("error: {error}\n"),
+                            };
+                            streamed.write_note(&mut lock, &note);
+                            streamed.errors.push((error, detail));
+                        }
+                    }
+                    flusher.wrote(&mut lock);
+                }
+                _ = tick.tick(), if !paced.is_empty() => {
+                    if let Some(c) = paced.pop_front() {
+                        self.write_delta(&mut lock, "", &c.to_string(), &mut streamed);
+                        flusher.wrote(&mut lock);
+                    }
+                }
+                _ = tokio::time::sleep_until(flusher.deadline), if flusher.unflushed > 0 => {
+                    flusher.flush(&mut lock);
+                }
+                // polled between the ticks, so a cancel doesn't wait for the queue to drain
+                Some(Ok(event::Event::Key(key))) = events.next() => {
+                    if key.modifiers.contains(event::KeyModifiers::CONTROL)
+                        && key.code == event::KeyCode::Char('c')
+                    {
+                        // what was streamed so far is still rendered as markdown below, even
+                        // when it ends mid-line or in an unclosed code block. the queued
+                        // typewriter text was never shown, it's dropped
+                        cancelled = true;
+                        break;
+                    }
+                    Self::buffer_typeahead(&mut self.typeahead, key);
                 }
             }
-            stdout().flush().unwrap();
         }
+        let (thought, content) = think.finish();
+        self.write_delta(&mut lock, &thought, &content, &mut streamed);
+        streamed.write_note(&mut lock, "");
+        flusher.flush(&mut lock);
+        let _ = disable_raw_mode();
 
-        let resp = ChatCompletionRequestAssistantMessageArgs::default()
-            .content(resp_buf.clone())
-            .build()
-            .unwrap();
-        self.history.push(resp.into());
-        self.render_resp(resp_buf.clone());
+        // a blank answer would only be a blank turn in the history
+        let end = if !streamed.answer.trim().is_empty() {
+            StreamEnd::Answer
+        } else if cancelled || !streamed.errors.is_empty() {
+            StreamEnd::Nothing
+        } else {
+            StreamEnd::Empty
+        };
+        self.truncated = end == StreamEnd::Answer && streamed.truncated;
+        if end == StreamEnd::Answer {
+            // with /continue, the response carries on the last one
+            let partial = self.continuing.take().unwrap_or_default();
+            let resp = ChatCompletionRequestAssistantMessageArgs::default()
+                .content(partial + &streamed.answer)
+                .build()
+                .unwrap();
+            self.history.push(resp.into());
+            if self.no_reflow {
+                // the streamed text stays as it is
+                println!();
+            } else if streamed.rendered == 0 || !streamed.shown.is_empty() {
+                // with --progressive, what's left after the last complete block
+                self.render_resp(&streamed.answer[streamed.rendered..], &streamed.shown);
+            }
+            if self.stats {
+                println!(
+                    "{}",
+                    style::Stylize::dim(render::reading_stats(&streamed.answer))
+                );
+            }
+            println!("\n");
+            if let Some(mut file) = self.tee.as_ref() {
+                // a blank line between the responses
+                if let Err(e) = file.write_all(b"\n\n") {
+                    eprintln!("failed to write the response to the --tee file: {e}");
+                }
+            }
+            if self.copy {
+                if let Err(e) = clipboard::write(&streamed.answer) {
+                    eprintln!("{e}");
+                }
+            }
+        } else if !streamed.shown.is_empty() && !self.no_reflow {
+            self.render_resp("", &streamed.shown);
+        }
+        // the raw stream they were written in is cleared by the rendering
+        if !self.no_reflow {
+            for (error, detail) in streamed.errors {
+                print_error(&error, self.verbose.then_some(detail.as_str()));
+            }
+        }
+        if cancelled {
+            println!("{}", self.skin.inline("*(response cancelled)*"));
+        }
+        end
     }
 
-    fn render_resp(&mut self, resp_buf: String) {
-        // count the number of lines in the response buffer
-        let screen_width = size().unwrap().0;
-        let mut resp_lines = 0_u16;
-        for line in resp_buf.lines() {
-            resp_lines += (line.len() as u16 / screen_width) + 1;
+    // write the reasoning and the content of a delta. the reasoning is shown dimmed above the
+    // answer (when asked for) and never makes it into the history
+    fn write_delta(
+        &self,
+        lock: &mut StdoutLock,
+        thought: &str,
+        content: &str,
+        streamed: &mut Streamed,
+    ) {
+        if (self.show_reasoning && !thought.is_empty()) || !content.is_empty() {
+            streamed.clear_waiting(lock);
+        }
+        // raw mode doesn't turn "\n" into "\r\n" for us
+        if self.show_reasoning && !thought.is_empty() {
+            let styled = style::Stylize::dim(thought.replace('\n', "\r\n"));
+            write!(lock, "{styled}").unwrap();
+            streamed.reasoning = true;
+        }
+        if !content.is_empty() {
+            if streamed.reasoning {
+                write!(lock, "\r\n\r\n").unwrap();
+                streamed.reasoning = false;
+            }
+            if let Some(mut file) = self.tee.as_ref() {
+                // the raw content, neither wrapped nor styled
+                let _ = file.write_all(content.as_bytes());
+            }
+            let mut rest = content;
+            while self.progressive {
+                let Some(end) = render::block_end(&streamed.answer[streamed.rendered..], rest)
+                else {
+                    break;
+                };
+                Self::write_content(lock, &rest[..end], streamed);
+                self.render_blocks(lock, streamed);
+                rest = &rest[end..];
+            }
+            Self::write_content(lock, rest, streamed);
         }
+    }
+
+    fn write_content(lock: &mut StdoutLock, content: &str, streamed: &mut Streamed) {
+        let text = match streamed.wrap.as_mut() {
+            Some(wrap) => wrap.push(content),
+            None => content.to_string(),
+        };
+        let styled = match streamed.light.as_mut() {
+            Some(light) => light.push(&text),
+            None => text.clone(),
+        };
+        write!(lock, "{}", styled.replace('\n', "\r\n")).unwrap();
+        streamed.answer.push_str(content);
+        streamed.shown.push_str(&text);
+    }
+
+    // render the blocks completed since the last ones in place of their raw text, the rest of
+    // the answer is streamed below them
+    fn render_blocks(&self, lock: &mut StdoutLock, streamed: &mut Streamed) {
+        let width = size().map_or(80, |(width, _)| width);
+        let rows = render::rows(&streamed.shown, width);
+        move_to_start(lock, rows);
+        let _ = queue!(lock, Clear(ClearType::FromCursorDown));
+        // the skin writes "\n" line breaks
+        let _ = disable_raw_mode();
+        self.print_markdown(&streamed.answer[streamed.rendered..]);
+        let _ = enable_raw_mode();
+        streamed.rendered = streamed.answer.len();
+        streamed.shown.clear();
+    }
 
-        if resp_lines < 1 {
-            resp_lines = 1;
+    // collect the key strokes typed while a response is streaming
+    fn buffer_typeahead(typeahead: &mut String, key: event::KeyEvent) {
+        if key.kind == KeyEventKind::Release || key.modifiers.contains(event::KeyModifiers::CONTROL)
+        {
+            return;
         }
+        match key.code {
+            event::KeyCode::Char(c) => typeahead.push(c),
+            event::KeyCode::Enter => typeahead.push('\n'),
+            event::KeyCode::Backspace | event::KeyCode::Delete => {
+                typeahead.pop();
+            }
+            _ => {}
+        }
+    }
+
+    // render the answer as markdown in place of the raw `shown` text
+    fn render_resp(&mut self, resp_buf: &str, shown: &str) {
+        // count the number of lines the raw text takes
+        let screen_width = size().map_or(80, |(width, _)| width);
+        let resp_lines = render::rows(shown, screen_width);
         //clean the raw content and reformat the whole content from gpt
-        let _ = queue!(
-            stdout(),
-            MoveToPreviousLine(resp_lines - 1),
-            MoveLeft(screen_width),
-            Clear(ClearType::FromCursorDown),
-        );
+        move_to_start(&mut stdout(), resp_lines);
+        let _ = queue!(stdout(), Clear(ClearType::FromCursorDown));
 
         // format the whole content as MD
-        self.skin.print_text(resp_buf.as_str());
-        stdout().flush().unwrap();
-        println!("\n");
+        self.print_markdown(resp_buf);
+        let _ = stdout().flush();
         // println!("response lines: {resp_lines} \t screen width: {screen_width}");
     }
 }
+
+// back to the start of a text taking `rows` rows, the cursor being on its last one. crossterm
+// moves by a row when asked to move by none
+fn move_to_start(out: &mut impl Write, rows: u16) {
+    if rows > 1 {
+        let _ = queue!(out, MoveToPreviousLine(rows - 1));
+    }
+    let _ = queue!(out, MoveToColumn(0));
+}
+
+// the version with the commit and the date it was built from (see build.rs), for the bug reports
+fn print_version() {
+    println!(
+        "cli-gpt {} ({} {})",
+        env!("CARGO_PKG_VERSION"),
+        env!("GIT_SHA"),
+        env!("BUILD_DATE")
+    );
+    println!("default model: {}", settings::DEFAULT_MODEL);
+    println!("async-openai: {}", env!("ASYNC_OPENAI_VERSION"));
+}
+
+// dump the effective settings and run-time options, with the api key redacted
+fn print_config(effective: &Effective, args: &AppArgs, format: ConfigFormat) {
+    let autosave = args.autosave_interval.map(|autosave| match autosave {
+        Autosave::Turns(turns) => format!("{turns}"),
+        Autosave::Every(interval) => format!("{}s", interval.as_secs()),
+    });
+    let config = serde_json::json!({
+        "profile": effective.profile,
+        "config_file": paths::config_file(),
+        "local_config": paths::local_config_file(),
+        "api_key": effective.api_key.as_ref().map(|key| settings::mask(&key.value)),
+        "api_base": effective.api_base.value,
+        "model": effective.model.value,
+        "favorite_models": effective.favorite_models.value,
+        "org_id": effective.org_id.as_ref().map(|org_id| &org_id.value),
+        "project_id": effective.project_id.as_ref().map(|project_id| &project_id.value),
+        "models": effective.models,
+        "temperature": args.temperature,
+        "top_p": args.top_p,
+        "max_tokens": args.max_tokens,
+        "reasoning_effort": args.reasoning_effort.map(|effort| effort.as_str()),
+        "verbosity": args.verbosity.map(|verbosity| verbosity.as_str()),
+        "tokens_field": args.tokens_field.map(|field| field.as_str()),
+        "developer": args.developer,
+        "instruction_role": args.instruction_role.map(|role| role.as_str()),
+        "session": args.session,
+        "autosave_interval": autosave,
+        "max_history_bytes": args.max_history_bytes,
+        "continue_from": args.continue_from,
+        "tail": args.tail,
+        "messages_file": args.messages_file,
+        "context": args.context,
+        "context_budget": args.context_budget,
+        "gitignore": !args.no_gitignore,
+        "persona": args.persona,
+        "personas_file": args.personas_file.clone().unwrap_or_else(paths::personas_file),
+        "send_key": args.send_key.map_or("auto", |key| key.describe()),
+        "max_line": args.max_line,
+        "prompt": effective.prompt,
+        "greeting": effective.greeting,
+        "waiting": effective.waiting,
+        "prepend": args.prepend.as_ref().or(effective.prepend.as_ref()),
+        "append": args.append.as_ref().or(effective.append.as_ref()),
+        "export_system": args.export_system || effective.export_system,
+        "role_tag": args.role_tag.as_str(),
+        "save_system": !args.no_save_system && effective.save_system,
+        "user_agent": effective.user_agent,
+        "aliases": effective.aliases,
+        "alias_sigil": effective.alias_sigil,
+        "theme": effective.theme,
+        "hyperlinks": !args.no_hyperlinks,
+        "wrap": args.wrap,
+        "progressive": args.progressive,
+        "reflow": !args.no_reflow,
+        "strip_markdown": args.strip_markdown,
+        "tui": args.tui,
+        "suffix": args.suffix,
+        "retry_empty": args.retry_empty,
+        "tee": args.tee,
+        "copy": args.copy,
+        "table_fit": args.table_fit.as_str(),
+        "moderate": args.moderate,
+        "diff": args.diff,
+        "echo_prompt": args.echo_prompt,
+        "show_reasoning": args.show_reasoning,
+        "verbose": args.verbose,
+        "stats": args.stats,
+        "auto_continue": args.auto_continue,
+        "show_rate_limit": args.show_rate_limit,
+        "pace": args.pace,
+        "typewriter": args.typewriter,
+        "confirm_over": (!args.yes).then_some(args.confirm_over),
+    });
+    // toml has no null, the unset options are left out
+    let config: serde_json::Map<_, _> = config
+        .as_object()
+        .unwrap()
+        .iter()
+        .filter(|(_, value)| !value.is_null())
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect();
+    match format {
+        ConfigFormat::Toml => print!("{}", toml::to_string(&config).unwrap()),
+        ConfigFormat::Json => println!("{}", serde_json::to_string_pretty(&config).unwrap()),
+    }
+}
+
+// a text as a markdown quote block
+fn quote(text: &str) -> String {
+    let lines: Vec<String> = text
+        .trim_end()
+        .lines()
+        .map(|l| format!("> {l}\n"))
+        .collect();
+    lines.concat()
+}
+
+// the text piped in, nothing when stdin is the terminal
+fn read_stdin() -> String {
+    let mut stdin = std::io::stdin();
+    let mut piped = String::new();
+    if stdin.is_terminal() {
+        return piped;
+    }
+    if let Err(e) = std::io::Read::read_to_string(&mut stdin, &mut piped) {
+        eprintln!("failed to read stdin: {e}");
+    }
+    piped
+}
+
+// the initial prompt: the positional args as the instruction, followed by whatever is piped in
+// as its body, a blank line apart (eg: `cat notes.md | cli-gpt summarize:`)
+fn initial_prompt(args: &[String], piped: &str) -> String {
+    let instruction = args.join(" ");
+    let body = piped.trim_end();
+    if instruction.is_empty() || body.is_empty() {
+        return instruction + body;
+    }
+    format!("{instruction}\n\n{body}")
+}
+
+// an error in bold red, with its details (eg: the whole api error) dimmed under it. the messages
+// are printed as is, they may hold markdown-like `_` and `*`
+fn print_error(message: &str, detail: Option<&str>) {
+    let label = style::Stylize::bold(style::Stylize::red("error:"));
+    eprintln!("{label} {message}");
+    if let Some(detail) = detail {
+        eprintln!("{}", style::Stylize::dim(detail));
+    }
+}
+
+// the text inside the code block the whole answer may still be wrapped in
+fn strip_fence(text: &str) -> &str {
+    let trimmed = text.trim();
+    if !trimmed.starts_with("```") || !trimmed.ends_with("```") || trimmed.len() < 6 {
+        return text;
+    }
+    let inner = trimmed.trim_end_matches('`');
+    match inner.split_once('\n') {
+        Some((_, body)) => body.strip_suffix('\n').unwrap_or(body),
+        None => text,
+    }
+}
+
+// the prompt with the aliases written after `sigil` (eg: `;;tldr`) replaced by their text, and
+// the names after it that aren't aliases. nothing is expanded with an empty sigil
+fn expand_aliases(
+    pmt: &str,
+    sigil: &str,
+    aliases: &std::collections::BTreeMap<String, String>,
+) -> (String, Vec<String>) {
+    let mut unknown = Vec::new();
+    if sigil.is_empty() {
+        return (pmt.to_string(), unknown);
+    }
+    let mut out = String::with_capacity(pmt.len());
+    let mut rest = pmt;
+    while let Some(at) = rest.find(sigil) {
+        out.push_str(&rest[..at]);
+        let after = &rest[at + sigil.len()..];
+        let len = after
+            .find(|c: char| !c.is_alphanumeric() && c != '-' && c != '_')
+            .unwrap_or(after.len());
+        let name = &after[..len];
+        match aliases.get(name) {
+            Some(text) => out.push_str(text),
+            None => {
+                if !name.is_empty() && !unknown.iter().any(|n| n == name) {
+                    unknown.push(name.to_string());
+                }
+                out.push_str(&rest[at..at + sigil.len() + len]);
+            }
+        }
+        rest = &after[len..];
+    }
+    out.push_str(rest);
+    (out, unknown)
+}
+
+// the `@key=value` words a prompt starts with, and the rest of it
+fn split_overrides(pmt: &str) -> (Vec<(&str, &str)>, &str) {
+    let mut overrides = Vec::new();
+    let mut rest = pmt.trim_start();
+    while let Some(word) = rest.split_whitespace().next() {
+        let Some((key, value)) = word.strip_prefix('@').and_then(|w| w.split_once('=')) else {
+            break;
+        };
+        overrides.push((key, value));
+        rest = rest[word.len()..].trim_start();
+    }
+    (overrides, rest)
+}
+
+// ask a yes/no question on the terminal, anything but y/yes is a no
+fn confirm(question: &str) -> bool {
+    eprint!("{question} [y/N] ");
+    let mut answer = String::new();
+    if std::io::stdin().read_line(&mut answer).is_err() {
+        return false;
+    }
+    matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
+// context window size (in tokens) of the known models
+fn context_window(model: &str) -> usize {
+    match model {
+        "gpt-4-1106-preview" => 128_000,
+        "gpt-3.5-turbo" => 4_096,
+        m if m.starts_with("gpt-4o") => 128_000,
+        m if is_reasoning_model(m) => 128_000,
+        m if m.starts_with("gpt-4") => 8_192,
+        _ => 4_096,
+    }
+}
+
+// price (in dollars) of a million input tokens of the known models
+fn input_price(model: &str) -> Option<f64> {
+    let prices = [
+        ("gpt-4o-mini", 0.15),
+        ("gpt-4o", 2.5),
+        ("gpt-4-1106-preview", 10.0),
+        ("gpt-4", 30.0),
+        ("gpt-3.5-turbo", 0.5),
+        ("o1-mini", 1.1),
+        ("o3-mini", 1.1),
+        ("o1", 15.0),
+        ("o3", 2.0),
+    ];
+    prices
+        .iter()
+        .find(|(prefix, _)| model.starts_with(prefix))
+        .map(|(_, price)| *price)
+}
+
+// the models of the openai api taking chat completions, going by their names
+fn is_chat_model(id: &str) -> bool {
+    let chat = id.starts_with("gpt-") || id.starts_with("chatgpt-") || is_reasoning_model(id);
+    let other = [
+        "instruct",
+        "audio",
+        "realtime",
+        "tts",
+        "transcribe",
+        "image",
+    ];
+    chat && !other.iter().any(|kind| id.contains(kind))
+}
+
+// the o-series models, they think before answering and take a different parameter set
+fn is_reasoning_model(model: &str) -> bool {
+    ["o1", "o3", "o4"]
+        .iter()
+        .any(|family| model == *family || model.starts_with(&format!("{family}-")))
+}
+
+fn takes_reasoning_effort(model: &str) -> bool {
+    is_reasoning_model(model) || model.starts_with("gpt-5")
+}
+
+fn takes_verbosity(model: &str) -> bool {
+    model.starts_with("gpt-5")
+}
+
+// the models only taking max_completion_tokens, the older ones may only know max_tokens
+fn rejects_max_tokens(model: &str) -> bool {
+    is_reasoning_model(model) || model.starts_with("gpt-5")
+}
+
+// the tokenizer of the gpt-4 and gpt-3.5 models, close enough for the others
+static TOKENIZER: OnceLock<Option<tiktoken_rs::CoreBPE>> = OnceLock::new();
+
+// token count of a message plus the per-message overhead. ~4 chars per token if the tokenizer
+// can't be loaded
+fn estimate_tokens(message: &ChatCompletionRequestMessage) -> usize {
+    let text = message_text(message);
+    let tokens = match TOKENIZER.get_or_init(|| tiktoken_rs::cl100k_base().ok()) {
+        Some(bpe) => bpe.encode_with_special_tokens(text).len(),
+        None => text.chars().count() / 4,
+    };
+    tokens + 4
+}
+
+fn message_role(message: &ChatCompletionRequestMessage) -> &'static str {
+    match message {
+        ChatCompletionRequestMessage::System(_) => "system",
+        ChatCompletionRequestMessage::User(_) => "user",
+        ChatCompletionRequestMessage::Assistant(_) => "assistant",
+        ChatCompletionRequestMessage::Tool(_) => "tool",
+        ChatCompletionRequestMessage::Function(_) => "function",
+    }
+}
+
+fn message_text(message: &ChatCompletionRequestMessage) -> &str {
+    match message {
+        ChatCompletionRequestMessage::User(m) => match &m.content {
+            Some(ChatCompletionRequestUserMessageContent::Text(text)) => text,
+            _ => "",
+        },
+        ChatCompletionRequestMessage::System(m) => m.content.as_deref().unwrap_or_default(),
+        ChatCompletionRequestMessage::Assistant(m) => m.content.as_deref().unwrap_or_default(),
+        ChatCompletionRequestMessage::Tool(m) => m.content.as_deref().unwrap_or_default(),
+        ChatCompletionRequestMessage::Function(m) => m.content.as_deref().unwrap_or_default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use api::{ChatChunk, ChunkChoice, ChunkDelta};
+
+    // an app with a config directory of its own, the responses written as they come
+    fn app() -> App {
+        let dir = std::env::temp_dir().join(format!("cli-gpt-test-{}", std::process::id()));
+        std::env::set_var("OPENAI_API_KEY", "sk-test");
+        let args = AppArgs::parse_from([
+            "cli-gpt",
+            "--config-dir",
+            dir.to_str().unwrap(),
+            "--no-reflow",
+        ]);
+        App::with_args(args, String::new())
+    }
+
+    // a response streaming these deltas, then failing with `error`
+    fn stream(deltas: &[&str], error: Option<&str>) -> ChatStream {
+        let mut items: Vec<Result<ChatChunk, OpenAIError>> = deltas
+            .iter()
+            .map(|content| {
+                let mut delta = ChunkDelta::default();
+                delta.content = Some(content.to_string());
+                let choice = ChunkChoice {
+                    delta,
+                    finish_reason: None,
+                };
+                Ok(ChatChunk {
+                    choices: vec![choice],
+                })
+            })
+            .collect();
+        items.extend(error.map(|e| Err(OpenAIError::StreamError(e.to_string()))));
+        Box::pin(futures::stream::iter(items))
+    }
+
+    // the history as `send_message` leaves it, the prompt waiting for its response
+    fn prompt(app: &mut App, text: &str) {
+        let message = ChatCompletionRequestUserMessageArgs::default()
+            .content(text)
+            .build()
+            .unwrap();
+        app.history.push(message.into());
+    }
+
+    #[tokio::test]
+    async fn respond_adds_the_answer() {
+        let mut app = app();
+        prompt(&mut app, "hi");
+        assert!(app.respond(Ok(stream(&["Hel", "lo ", "👋"], None))).await);
+        assert_eq!(app.history.len(), 2);
+        assert_eq!(message_role(&app.history[1]), "assistant");
+        assert_eq!(message_text(&app.history[1]), "Hello 👋");
+    }
+
+    #[tokio::test]
+    async fn edit_last_puts_back_the_unanswered_exchange() {
+        let mut app = app();
+        prompt(&mut app, "hi");
+        assert!(app.respond(Ok(stream(&["hello"], None))).await);
+        // as /edit-last leaves it, the reworded prompt being sent
+        let exchange = app.history.split_off(0);
+        let before = app.history.len();
+        prompt(&mut app, "hi there");
+        let answered = app.respond(Ok(stream(&[], Some("connection reset")))).await;
+        app.put_back(before, answered, exchange);
+        let texts: Vec<&str> = app.history.iter().map(message_text).collect();
+        assert_eq!(texts, ["hi", "hello"]);
+    }
+
+    #[tokio::test]
+    async fn respond_leaves_the_failures_out() {
+        let mut app = app();
+        prompt(&mut app, "hi");
+        let failed = stream(&[], Some("connection reset"));
+        assert!(!app.respond(Ok(failed)).await);
+        let refused = OpenAIError::InvalidArgument("the request was cancelled".to_string());
+        assert!(!app.respond(Err(refused)).await);
+        let blank = stream(&[" ", "\n"], None);
+        assert!(!app.respond(Ok(blank)).await);
+        assert_eq!(app.history.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn serve_turns_down_a_request_while_one_is_in_flight() {
+        use tokio::{
+            io::{AsyncReadExt, AsyncWriteExt},
+            net::{TcpListener, TcpStream},
+        };
+
+        // an api taking the request and never answering it
+        let api = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let api_base = format!("http://{}/v1", api.local_addr().unwrap());
+        let (sent, received) = tokio::sync::oneshot::channel();
+        tokio::spawn(async move {
+            let _socket = api.accept().await.unwrap();
+            let _ = sent.send(());
+            std::future::pending::<()>().await
+        });
+        let mut app = app();
+        app.serve_addr = Some("127.0.0.1:0".to_string());
+        let config = OpenAIConfig::new()
+            .with_api_key("sk-test")
+            .with_api_base(api_base);
+        app.client =
+            Client::with_config(ApiConfig::new(config, None)).with_http_client(app.http.clone());
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let post = |pmt: &'static str| async move {
+            let mut socket = TcpStream::connect(addr).await.unwrap();
+            let req = format!(
+                "POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n{pmt}",
+                pmt.len()
+            );
+            socket.write_all(req.as_bytes()).await.unwrap();
+            socket
+        };
+        let client = async {
+            let _first = post("hi").await;
+            received.await.unwrap();
+            let mut second = post("hello").await;
+            let mut resp = String::new();
+            second.read_to_string(&mut resp).await.unwrap();
+            resp
+        };
+        let resp = tokio::select! {
+            _ = app.serve_on(listener) => unreachable!(),
+            resp = client => resp,
+        };
+        assert!(resp.starts_with("HTTP/1.1 503"), "{resp}");
+    }
+}
@@ -0,0 +1,97 @@
+use directories::ProjectDirs;
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+// every file the app reads or writes is resolved here. the directories crate follows
+// XDG_CONFIG_HOME/XDG_DATA_HOME on linux and the platform conventions on macos/windows, and
+// `--config-dir` puts everything under a single directory instead
+static CONFIG_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+pub fn set_config_dir(dir: PathBuf) {
+    let _ = CONFIG_DIR.set(dir);
+}
+
+// the same qualifier as confy used, so existing config files are still found
+fn project_dirs() -> ProjectDirs {
+    ProjectDirs::from("rs", "", "cli-gpt").expect("no home directory found")
+}
+
+pub fn config_dir() -> PathBuf {
+    match CONFIG_DIR.get() {
+        Some(dir) => dir.clone(),
+        None => project_dirs().config_dir().to_path_buf(),
+    }
+}
+
+pub fn data_dir() -> PathBuf {
+    match CONFIG_DIR.get() {
+        Some(dir) => dir.clone(),
+        None => project_dirs().data_dir().to_path_buf(),
+    }
+}
+
+pub fn config_file() -> PathBuf {
+    config_dir().join("config.toml")
+}
+
+pub fn personas_file() -> PathBuf {
+    config_dir().join("personas.toml")
+}
+
+pub fn sessions_dir() -> PathBuf {
+    data_dir().join("sessions")
+}
+
+// a session name is a file name in the sessions dir, never a path leading out of it
+pub fn check_session_name(name: &str) -> Result<(), String> {
+    if name.is_empty() || name == "." || name.contains("..") || name.contains(['/', '\\']) {
+        return Err(format!(
+            "{name:?} is not a valid session name, it can't be empty or hold `/`, `\\` or `..`"
+        ));
+    }
+    Ok(())
+}
+
+pub fn session_file(name: &str) -> PathBuf {
+    sessions_dir().join(format!("{name}.json"))
+}
+
+// the project config, `.cli-gpt.toml` in the current directory or the closest of its parents,
+// looking no further than the root of the git repository
+pub fn local_config_file() -> Option<PathBuf> {
+    let cwd = std::env::current_dir().ok()?;
+    for dir in cwd.ancestors() {
+        let file = dir.join(".cli-gpt.toml");
+        if file.is_file() {
+            return Some(file);
+        }
+        if dir.join(".git").exists() {
+            break;
+        }
+    }
+    None
+}
+
+// create a directory (and its parents) only readable by the user
+pub fn create_dir(dir: &Path) -> io::Result<()> {
+    let mut builder = fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+    builder.create(dir)
+}
+
+// make a file holding secrets (eg: the api key) only readable by the user
+pub fn restrict_file(path: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
@@ -0,0 +1,25 @@
+use serde_derive::Deserialize;
+use std::{collections::BTreeMap, fs, io::ErrorKind, path::PathBuf};
+
+// a named system prompt, loaded from the personas file, eg:
+//
+//  [reviewer]
+//  description = "strict code reviewer"
+//  prompt = "You review code. Point out bugs first, style last."
+#[derive(Deserialize, Debug, Clone)]
+pub struct Persona {
+    pub prompt: String,
+    #[serde(default)]
+    pub description: String,
+}
+
+pub type Personas = BTreeMap<String, Persona>;
+
+// a missing file just means no personas were defined
+pub fn load_personas(path: &PathBuf) -> Result<Personas, String> {
+    match fs::read_to_string(path) {
+        Ok(text) => toml::from_str(&text).map_err(|e| format!("{}: {e}", path.display())),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(Personas::new()),
+        Err(e) => Err(format!("{}: {e}", path.display())),
+    }
+}
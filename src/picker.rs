@@ -0,0 +1,63 @@
+use std::io::{stdout, Write};
+use termimad::crossterm::{
+    cursor,
+    event::{self, Event, KeyCode, KeyEventKind, KeyModifiers},
+    queue,
+    style::Stylize,
+    terminal::{disable_raw_mode, enable_raw_mode, Clear, ClearType},
+};
+
+// how many choices are shown at once, the list scrolls past them
+const ROWS: usize = 10;
+
+// let the user pick one of `choices` with the arrow keys and enter, starting on `selected`.
+// None when dismissed with esc or ctrl-c
+pub fn pick(title: &str, choices: &[String], mut selected: usize) -> Option<usize> {
+    if choices.is_empty() {
+        return None;
+    }
+    let rows = ROWS.min(choices.len());
+    let mut out = stdout();
+    println!("{title}");
+    let _ = enable_raw_mode();
+    let mut top = 0;
+    let picked = loop {
+        if selected < top {
+            top = selected;
+        } else if selected >= top + rows {
+            top = selected + 1 - rows;
+        }
+        for (i, choice) in choices.iter().enumerate().skip(top).take(rows) {
+            let _ = queue!(out, Clear(ClearType::CurrentLine));
+            if i == selected {
+                let _ = write!(out, "{}\r\n", format!("> {choice}").bold());
+            } else {
+                let _ = write!(out, "  {choice}\r\n");
+            }
+        }
+        let _ = out.flush();
+        let key = event::read();
+        // drawn again over the same lines
+        let _ = queue!(out, cursor::MoveToPreviousLine(rows as u16));
+        let Ok(Event::Key(key)) = key else {
+            continue;
+        };
+        if key.kind == KeyEventKind::Release {
+            continue;
+        }
+        match key.code {
+            KeyCode::Up => selected = selected.saturating_sub(1),
+            KeyCode::Down => selected = (selected + 1).min(choices.len() - 1),
+            KeyCode::PageUp => selected = selected.saturating_sub(rows),
+            KeyCode::PageDown => selected = (selected + rows).min(choices.len() - 1),
+            KeyCode::Enter => break Some(selected),
+            KeyCode::Esc => break None,
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => break None,
+            _ => {}
+        }
+    };
+    let _ = queue!(out, Clear(ClearType::FromCursorDown));
+    let _ = out.flush();
+    let _ = disable_raw_mode();
+    picked
+}
@@ -0,0 +1,989 @@
+use similar::{ChangeTag, TextDiff};
+use std::io::{stdout, Write};
+use termimad::{
+    crossterm::style::{Color, Stylize},
+    MadSkin,
+};
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+
+use crate::{settings::Theme, App, TableFit};
+
+// the skin the markdown is rendered with, from the theme of the settings. the values that make
+// no sense are warned about and left out
+pub fn skin(theme: &Theme) -> MadSkin {
+    let mut skin = match theme.preset.as_deref().unwrap_or("default") {
+        "plain" => MadSkin::no_style(),
+        "light" => {
+            let mut skin = MadSkin::default_light();
+            skin.set_fg(Color::DarkBlue);
+            skin.set_headers_fg(Color::DarkMagenta);
+            skin
+        }
+        preset => {
+            if preset != "default" {
+                eprintln!("unknown theme preset {preset:?}, expected default, plain or light");
+            }
+            let mut skin = MadSkin::default();
+            skin.set_fg(Color::DarkCyan);
+            skin
+        }
+    };
+    let color = |key: &str, value: &Option<String>| {
+        let value = value.as_deref()?;
+        let color = parse_color(value);
+        if color.is_none() {
+            eprintln!("theme: {key} = {value:?} is not a color, ignored");
+        }
+        color
+    };
+    if let Some(text) = color("text", &theme.text) {
+        skin.set_fg(text);
+    }
+    if let Some(headers) = color("headers", &theme.headers) {
+        skin.set_headers_fg(headers);
+    }
+    if let Some(bold) = color("bold", &theme.bold) {
+        skin.bold.set_fg(bold);
+    }
+    if let Some(italic) = color("italic", &theme.italic) {
+        skin.italic.set_fg(italic);
+    }
+    if let Some(code) = color("code", &theme.code) {
+        skin.inline_code.set_fg(code);
+        skin.code_block.set_fg(code);
+    }
+    if let Some(bullet) = theme.bullet {
+        skin.bullet.set_char(bullet);
+    }
+    if let Some(quote_mark) = theme.quote_mark {
+        skin.quote_mark.set_char(quote_mark);
+    }
+    skin
+}
+
+// a color name (eg: "dark_cyan"), "#rrggbb" or an ansi number
+fn parse_color(text: &str) -> Option<Color> {
+    if let Some(hex) = text.strip_prefix('#') {
+        let channel = |i: usize| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok();
+        return match hex.len() {
+            6 => Some(Color::Rgb {
+                r: channel(0)?,
+                g: channel(2)?,
+                b: channel(4)?,
+            }),
+            _ => None,
+        };
+    }
+    match text.parse::<u8>() {
+        Ok(ansi) => Some(Color::AnsiValue(ansi)),
+        Err(_) => Color::try_from(text).ok(),
+    }
+}
+
+// a piece of a markdown response, the fenced code is printed verbatim since termimad's reflow
+// can break its indentation
+pub enum Segment {
+    Prose(String),
+    Code(Vec<String>),
+}
+
+// split a markdown text on its fenced code blocks (``` or ~~~), `$$` display math blocks are
+// handled the same way. an unterminated fence runs to the end of the text
+pub fn split_code_blocks(md: &str) -> Vec<Segment> {
+    let mut segments = Vec::new();
+    let mut prose = String::new();
+    let mut code: Option<(String, Vec<String>)> = None; // the opening fence and the lines
+    for line in md.lines() {
+        let trimmed = line.trim_start();
+        match code.as_mut() {
+            Some((fence, lines)) => {
+                if trimmed.starts_with(fence.as_str()) && trimmed.trim_end() == fence.as_str() {
+                    segments.push(Segment::Code(std::mem::take(lines)));
+                    code = None;
+                } else {
+                    lines.push(line.replace('\t', "    "));
+                }
+            }
+            None => {
+                let fence: String = if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+                    let c = trimmed.chars().next().unwrap();
+                    trimmed.chars().take_while(|&x| x == c).collect()
+                } else if trimmed.trim_end() == "$$" {
+                    "$$".to_string()
+                } else {
+                    prose.push_str(line);
+                    prose.push('\n');
+                    continue;
+                };
+                if !prose.is_empty() {
+                    segments.push(Segment::Prose(std::mem::take(&mut prose)));
+                }
+                code = Some((fence, Vec::new()));
+            }
+        }
+    }
+    if let Some((_, lines)) = code {
+        segments.push(Segment::Code(lines));
+    }
+    if !prose.is_empty() {
+        segments.push(Segment::Prose(prose));
+    }
+    segments
+}
+
+// where the markdown blocks of a streamed answer end: the length of `next` up to the first blank
+// line that closes a block, `pending` being the text streamed since the last one. the blank
+// lines in fenced code don't count
+pub fn block_end(pending: &str, next: &str) -> Option<usize> {
+    let text = format!("{pending}{next}");
+    let mut fence: Option<String> = None;
+    let mut start = 0;
+    for line in text.split_inclusive('\n') {
+        let end = start + line.len();
+        if !line.ends_with('\n') {
+            break;
+        }
+        let trimmed = line.trim();
+        match &fence {
+            Some(open) => {
+                if trimmed == open {
+                    fence = None;
+                }
+            }
+            None if trimmed.starts_with("```") || trimmed.starts_with("~~~") => {
+                let c = trimmed.chars().next().unwrap();
+                fence = Some(trimmed.chars().take_while(|&x| x == c).collect());
+            }
+            None if trimmed == "$$" => fence = Some("$$".to_string()),
+            None if trimmed.is_empty()
+                && end > pending.len()
+                && !text[..start].trim().is_empty() =>
+            {
+                return Some(end - pending.len());
+            }
+            None => {}
+        }
+        start = end;
+    }
+    None
+}
+
+// turn the inline math (`$...$` and `\(...\)`) into inline code, so the `*`, `_` and `\` in
+// formulas aren't taken for markdown. a `$` only opens math when followed by a non space char
+// and only closes it when preceded by one and not followed by a digit, so prices stay as is
+pub fn inline_math(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let chars: Vec<char> = text.chars().collect();
+    let mut i = 0;
+    let mut in_code = false;
+    while i < chars.len() {
+        let c = chars[i];
+        if c == '`' {
+            in_code = !in_code;
+        } else if !in_code && c == '\\' && chars.get(i + 1) == Some(&'(') {
+            if let Some(end) = find(&chars, i + 2, &['\\', ')']) {
+                let formula: String = chars[i + 2..end].iter().collect();
+                out.push_str(&format!("`{}`", formula.trim()));
+                i = end + 2;
+                continue;
+            }
+        } else if !in_code
+            && c == '$'
+            && chars
+                .get(i + 1)
+                .is_some_and(|n| !n.is_whitespace() && *n != '$')
+        {
+            let close = (i + 2..chars.len()).find(|&j| {
+                chars[j] == '$'
+                    && !chars[j - 1].is_whitespace()
+                    && !chars.get(j + 1).is_some_and(|n| n.is_ascii_digit())
+            });
+            if let Some(end) = close {
+                let formula: String = chars[i + 1..end].iter().collect();
+                // `$5 and $10` isn't math
+                let price = formula.starts_with(|c: char| c.is_ascii_digit())
+                    && formula.contains(char::is_whitespace);
+                if !price && !formula.contains('\n') && !formula.contains('`') {
+                    out.push_str(&format!("`{formula}`"));
+                    i = end + 1;
+                    continue;
+                }
+            }
+        }
+        out.push(c);
+        i += 1;
+    }
+    out
+}
+
+// give the GFM tables the outer pipes termimad needs to tell them apart from prose, eg:
+//
+//  name | size          |name | size|
+//  :--- | ---:    =>    |:--- | ---:|
+//  a    | 1             |a    | 1|
+//
+// a table is a row with a pipe followed by a delimiter row, and runs to the first line without
+// one. termimad then aligns the columns and wraps the cells of the tables too wide to fit, unless
+// `fit` asks for the wide tables to be truncated or laid out vertically
+pub fn tables(text: &str, fit: TableFit, width: usize) -> String {
+    let lines: Vec<&str> = text.lines().collect();
+    let mut out = String::with_capacity(text.len());
+    let mut i = 0;
+    while i < lines.len() {
+        if lines[i].contains('|') && lines.get(i + 1).is_some_and(|l| is_delimiter_row(l)) {
+            let mut rows = Vec::new();
+            while i < lines.len() && lines[i].contains('|') {
+                rows.push(cells(lines[i]));
+                i += 1;
+            }
+            let widths = column_widths(&rows);
+            // the cells plus a pipe before each of them and one closing the row
+            let table_width = widths.iter().sum::<usize>() + widths.len() + 1;
+            match fit {
+                TableFit::Truncate if table_width > width => truncate_table(&mut rows, width),
+                TableFit::Vertical if table_width > width => {
+                    out.push_str(&vertical_table(&rows));
+                    continue;
+                }
+                _ => {}
+            }
+            for row in rows {
+                out.push_str(&format!("|{}|\n", row.join("|")));
+            }
+            continue;
+        }
+        out.push_str(lines[i]);
+        out.push('\n');
+        i += 1;
+    }
+    out
+}
+
+// the cells of a table row, without the outer pipes
+fn cells(line: &str) -> Vec<String> {
+    let row = line.trim();
+    let row = row.strip_prefix('|').unwrap_or(row);
+    let row = row.strip_suffix('|').unwrap_or(row);
+    row.split('|').map(String::from).collect()
+}
+
+// the display width of each column, the delimiter row left out
+fn column_widths(rows: &[Vec<String>]) -> Vec<usize> {
+    let mut widths = Vec::new();
+    for (r, row) in rows.iter().enumerate() {
+        if r == 1 {
+            continue;
+        }
+        for (c, cell) in row.iter().enumerate() {
+            let cell_width = cell.trim().width();
+            match widths.get_mut(c) {
+                Some(width) => *width = cell_width.max(*width),
+                None => widths.push(cell_width),
+            }
+        }
+    }
+    widths
+}
+
+// shrink the widest columns until the table fits in `width`, the cells cut short end with `…`
+fn truncate_table(rows: &mut [Vec<String>], width: usize) {
+    const MIN_COLUMN: usize = 3;
+    let mut widths = column_widths(rows);
+    let available = width.saturating_sub(widths.len() + 1);
+    while widths.iter().sum::<usize>() > available {
+        let Some(widest) = (0..widths.len()).max_by_key(|&c| widths[c]) else {
+            break;
+        };
+        if widths[widest] <= MIN_COLUMN {
+            break;
+        }
+        widths[widest] -= 1;
+    }
+    for (r, row) in rows.iter_mut().enumerate() {
+        if r == 1 {
+            continue;
+        }
+        for (cell, &max) in row.iter_mut().zip(widths.iter()) {
+            *cell = ellipsize(cell.trim(), max);
+        }
+    }
+}
+
+// `text` cut to `max` columns, ending with `…` when it was cut
+fn ellipsize(text: &str, max: usize) -> String {
+    if text.width() <= max {
+        return text.to_string();
+    }
+    let mut out = String::new();
+    let mut used = 0;
+    for c in text.chars() {
+        let w = c.width().unwrap_or(0);
+        if used + w + 1 > max {
+            break;
+        }
+        out.push(c);
+        used += w;
+    }
+    out.push('…');
+    out
+}
+
+// a table as one `header: value` block per row, for the tables too wide for the terminal
+fn vertical_table(rows: &[Vec<String>]) -> String {
+    let Some(headers) = rows.first() else {
+        return String::new();
+    };
+    let mut out = String::new();
+    for row in rows.iter().skip(2) {
+        for (header, cell) in headers.iter().zip(row.iter()) {
+            out.push_str(&format!("**{}**: {}\n", header.trim(), cell.trim()));
+        }
+        out.push('\n');
+    }
+    out
+}
+
+// the row under a table header, eg: `| --- | :---: |`
+fn is_delimiter_row(line: &str) -> bool {
+    let row = line.trim();
+    let row = row.strip_prefix('|').unwrap_or(row);
+    let row = row.strip_suffix('|').unwrap_or(row);
+    line.contains('|')
+        && row.split('|').all(|cell| {
+            let dashes = cell.trim().trim_start_matches(':').trim_end_matches(':');
+            !dashes.is_empty() && dashes.chars().all(|c| c == '-')
+        })
+}
+
+// the bounds of a link's label in a text laid out by termimad. zero width, they change neither
+// the wrapping nor the table cells
+const LINK_START: char = '\u{2063}';
+const LINK_END: char = '\u{2064}';
+
+// spell out the markdown links `[text](url)` as `text (url)`, for the terminals not supporting
+// hyperlinks
+pub fn spell_out_links(text: &str) -> String {
+    replace_links(text, |label, url| {
+        if label == url {
+            url
+        } else {
+            format!("{label} ({url})")
+        }
+    })
+}
+
+// the markdown links `[text](url)` as their `text` between `LINK_START` and `LINK_END`, and the
+// urls in their order. the escape codes of the hyperlinks are only put in by `hyperlinks` once
+// termimad laid out the text, for them not to be taken for markup or counted in the width
+pub fn mark_links(text: &str) -> (String, Vec<String>) {
+    let mut urls = vec![];
+    let text = replace_links(text, |label, url| {
+        urls.push(url);
+        format!("{LINK_START}{label}{LINK_END}")
+    });
+    (text, urls)
+}
+
+// the labels marked by `mark_links` in a laid out text as OSC 8 terminal hyperlinks to `urls`
+pub fn hyperlinks(text: &str, urls: &[String]) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut urls = urls.iter();
+    for c in text.chars() {
+        match c {
+            LINK_START => {
+                let url = urls.next().map_or("", |url| url.as_str());
+                out.push_str(&format!("\x1b]8;;{url}\x1b\\"));
+            }
+            LINK_END => out.push_str("\x1b]8;;\x1b\\"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+// the text with its markdown links replaced by `link(label, url)`. links in code spans are left
+// as is
+fn replace_links(text: &str, mut link: impl FnMut(String, String) -> String) -> String {
+    let mut out = String::with_capacity(text.len());
+    let chars: Vec<char> = text.chars().collect();
+    let mut i = 0;
+    let mut in_code = false;
+    while i < chars.len() {
+        let c = chars[i];
+        if c == '`' {
+            in_code = !in_code;
+        } else if c == '\n' {
+            // an unclosed code span doesn't go past its line
+            in_code = false;
+        } else if !in_code && c == '[' {
+            if let Some((label, url, end)) = parse_link(&chars, i) {
+                out.push_str(&link(label, url));
+                i = end;
+                continue;
+            }
+        }
+        out.push(c);
+        i += 1;
+    }
+    out
+}
+
+// the label, the url and the index right after a `[label](url)` link starting at `start`
+fn parse_link(chars: &[char], start: usize) -> Option<(String, String, usize)> {
+    let close = find(chars, start + 1, &[']', '('])?;
+    let label: String = chars[start + 1..close].iter().collect();
+    let end = (close + 2..chars.len()).find(|&j| chars[j] == ')')?;
+    let url: String = chars[close + 2..end].iter().collect();
+    if label.contains('\n') || url.is_empty() || url.contains(char::is_whitespace) {
+        return None;
+    }
+    Some((label, url, end + 1))
+}
+
+fn find(chars: &[char], from: usize, needle: &[char]) -> Option<usize> {
+    (from..chars.len().saturating_sub(needle.len() - 1))
+        .find(|&j| chars[j..j + needle.len()] == *needle)
+}
+
+// the text without its terminal escape codes: the styling ones (eg: `\x1b[31m`) and the OSC
+// ones, of which the hyperlinks keep their label. for the files written, which the escapes
+// pasted or piped in (eg: `ls --color`) would clutter
+pub fn strip_ansi(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\x1b' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            // CSI, up to its final byte
+            Some('[') => {
+                for c in chars.by_ref() {
+                    if ('\x40'..='\x7e').contains(&c) {
+                        break;
+                    }
+                }
+            }
+            // OSC, up to BEL or ST (`ESC \`)
+            Some(']') => {
+                while let Some(c) = chars.next() {
+                    if c == '\x07' || (c == '\x1b' && chars.next_if_eq(&'\\').is_some()) {
+                        break;
+                    }
+                }
+            }
+            // a two chars escape (eg: `ESC 7`)
+            _ => {}
+        }
+    }
+    out
+}
+
+// the markdown as plain text, for the tools that would choke on its syntax: the headings as
+// plain lines, the list items starting with dashes, the code blocks indented and the tables as
+// tab separated cells, without emphasis, code span or link markers
+pub fn strip_markdown(md: &str) -> String {
+    let mut out = String::with_capacity(md.len());
+    for segment in split_code_blocks(md) {
+        match segment {
+            Segment::Prose(prose) => {
+                for line in prose.lines().filter(|line| !is_delimiter_row(line)) {
+                    out.push_str(&plain_line(line));
+                    out.push('\n');
+                }
+            }
+            Segment::Code(lines) => {
+                for line in lines {
+                    out.push_str(format!("    {line}").trim_end());
+                    out.push('\n');
+                }
+            }
+        }
+    }
+    out
+}
+
+// a prose line without its block markers
+fn plain_line(line: &str) -> String {
+    let text = line.trim_start();
+    let indent = &line[..line.len() - text.len()];
+    let mut text = text.trim_end();
+    while let Some(quoted) = text.strip_prefix('>') {
+        text = quoted.trim_start();
+    }
+    let hashes = text.chars().take_while(|&c| c == '#').count();
+    let marker: String = text.chars().filter(|c| !c.is_whitespace()).collect();
+    if (1..=6).contains(&hashes) && text[hashes..].starts_with([' ', '\t']) {
+        text = text[hashes..].trim().trim_end_matches('#').trim_end();
+    } else if marker.len() >= 3
+        && ["-", "*", "_"]
+            .iter()
+            .any(|c| marker == c.repeat(marker.len()))
+    {
+        // a thematic break
+        return String::new();
+    } else if let Some(item) = ["- ", "* ", "+ "].iter().find_map(|m| text.strip_prefix(m)) {
+        return format!("{indent}- {}", plain_inline(item));
+    } else if text.starts_with('|') {
+        let cells: Vec<String> = cells(text)
+            .iter()
+            .map(|cell| plain_inline(cell.trim()))
+            .collect();
+        return cells.join("\t");
+    }
+    format!("{indent}{}", plain_inline(text))
+}
+
+// a line without its emphasis, strikethrough, code span and link markers. a `*` or `_` between
+// spaces or inside a word (eg: `2*3`, `snake_case`) is kept
+fn plain_inline(text: &str) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    let mut out = String::with_capacity(text.len());
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let run = chars[i..].iter().take_while(|&&x| x == c).count();
+        match c {
+            '\\' if chars.get(i + 1).is_some_and(char::is_ascii_punctuation) => {
+                out.push(chars[i + 1]);
+                i += 2;
+                continue;
+            }
+            '`' => {
+                let close = (i + run..chars.len()).find(|&j| {
+                    chars[j..].iter().take_while(|&&x| x == '`').count() == run
+                        && (j == 0 || chars[j - 1] != '`')
+                });
+                if let Some(close) = close {
+                    let code: String = chars[i + run..close].iter().collect();
+                    out.push_str(&code);
+                    i = close + run;
+                    continue;
+                }
+            }
+            '!' if chars.get(i + 1) == Some(&'[') => {
+                if let Some((alt, _, end)) = parse_link(&chars, i + 1) {
+                    out.push_str(&plain_inline(&alt));
+                    i = end;
+                    continue;
+                }
+            }
+            '[' => {
+                if let Some((label, url, end)) = parse_link(&chars, i) {
+                    let label = plain_inline(&label);
+                    if label == url {
+                        out.push_str(&url);
+                    } else {
+                        out.push_str(&format!("{label} ({url})"));
+                    }
+                    i = end;
+                    continue;
+                }
+            }
+            '~' if run == 2 => {
+                i += run;
+                continue;
+            }
+            '*' | '_' if run <= 3 => {
+                let before = i.checked_sub(1).map(|j| chars[j]);
+                let after = chars.get(i + run).copied();
+                let space = |c: Option<char>| c.is_none_or(char::is_whitespace);
+                let word = |c: Option<char>| c.is_some_and(char::is_alphanumeric);
+                if !(space(before) && space(after) || word(before) && word(after)) {
+                    i += run;
+                    continue;
+                }
+            }
+            _ => {}
+        }
+        out.extend(&chars[i..i + run]);
+        i += run;
+    }
+    out
+}
+
+// number of terminal rows a text takes once wrapped, counted in columns rather than bytes so
+// the wide chars (eg: CJK, emoji) don't throw it off. a wide char not fitting at the end of a
+// row goes whole to the next one, and a trailing newline leaves the cursor on a row of its own.
+// the styling escape codes (eg: `\x1b[2m`) take no room
+pub fn rows(text: &str, width: u16) -> u16 {
+    let width = width.max(1) as usize;
+    let mut rows = 0_usize;
+    for line in text.split('\n') {
+        let mut col = 0;
+        let mut escape = false;
+        rows += 1;
+        for c in line.chars() {
+            if c == '\x1b' || escape {
+                escape = !(escape && c.is_ascii_alphabetic());
+                continue;
+            }
+            let w = c.width().unwrap_or(0);
+            if col + w > width {
+                rows += 1;
+                col = 0;
+            }
+            col += w;
+        }
+    }
+    rows.clamp(1, u16::MAX as usize) as u16
+}
+
+// soft wraps the streamed text at the terminal width, breaking at the spaces. a word is held
+// back until it's over, to know whether it still fits on the line
+pub struct StreamWrap {
+    width: usize,
+    col: usize,
+    word: String,
+}
+
+impl StreamWrap {
+    pub fn new(width: u16) -> Self {
+        Self {
+            width: width.max(1) as usize,
+            col: 0,
+            word: String::new(),
+        }
+    }
+
+    // the text to write for a delta, with the line breaks put in
+    pub fn push(&mut self, delta: &str) -> String {
+        let mut out = String::new();
+        for c in delta.chars() {
+            if !c.is_whitespace() {
+                self.word.push(c);
+                continue;
+            }
+            self.place_word(&mut out);
+            if c == '\n' {
+                out.push('\n');
+                self.col = 0;
+            } else if self.col + 1 >= self.width {
+                // the space at the end of a line is the break
+                out.push('\n');
+                self.col = 0;
+            } else {
+                out.push(c);
+                self.col += c.width().unwrap_or(0);
+            }
+        }
+        out
+    }
+
+    // what's held back, once the stream is over or before writing something else
+    pub fn finish(&mut self) -> String {
+        let mut out = String::new();
+        self.place_word(&mut out);
+        self.col = 0;
+        out
+    }
+
+    fn place_word(&mut self, out: &mut String) {
+        let width = self.word.width();
+        if self.col > 0 && self.col + width > self.width {
+            out.push('\n');
+            self.col = 0;
+        }
+        out.push_str(&self.word);
+        // a word longer than a line is wrapped by the terminal, its wide chars going whole to
+        // the next row as in `rows`
+        for c in self.word.chars() {
+            let w = c.width().unwrap_or(0);
+            if self.col + w > self.width {
+                self.col = 0;
+            }
+            self.col += w;
+        }
+        self.word.clear();
+    }
+}
+
+// light styling of the streamed markdown for --no-reflow, where it's never rendered: the
+// headings in bold, the quotes dimmed and the fenced code colored. a line's style is picked from
+// its first char, so the text is still written as soon as it comes
+#[derive(Default)]
+pub struct LightStyle {
+    line: String, // the line being written, unstyled
+    code: bool,   // in a fenced code block
+    styled: bool, // a style is on until the end of the line
+}
+
+impl LightStyle {
+    // the text to write for a delta, with the styling escapes put in
+    pub fn push(&mut self, delta: &str) -> String {
+        let mut out = String::new();
+        for c in delta.chars() {
+            if c == '\n' {
+                out.push_str(self.end());
+                out.push(c);
+                let line = self.line.trim_start();
+                if line.starts_with("```") || line.starts_with("~~~") {
+                    self.code = !self.code;
+                }
+                self.line.clear();
+                continue;
+            }
+            if !self.styled && !c.is_whitespace() && self.line.trim().is_empty() {
+                let style = match c {
+                    _ if self.code => Some("\x1b[36m"),
+                    '`' | '~' => Some("\x1b[36m"),
+                    '#' => Some("\x1b[1m"),
+                    '>' => Some("\x1b[2m"),
+                    _ => None,
+                };
+                if let Some(style) = style {
+                    out.push_str(style);
+                    self.styled = true;
+                }
+            }
+            out.push(c);
+            self.line.push(c);
+        }
+        out
+    }
+
+    // turn the style off, at the end of a line or before something else is written
+    pub fn end(&mut self) -> &'static str {
+        match std::mem::take(&mut self.styled) {
+            true => "\x1b[0m",
+            false => "",
+        }
+    }
+}
+
+// word count and reading time of a response, at ~200 words a minute
+pub fn reading_stats(text: &str) -> String {
+    let words = text.split_whitespace().count();
+    let minutes = words.div_ceil(200).max(1);
+    format!("{words} words · ~{minutes} min read")
+}
+
+// splits the `<think>...</think>` blocks some models put in the content from the answer, the
+// tags may be cut anywhere across the deltas
+#[derive(Default)]
+pub struct ThinkSplitter {
+    thinking: bool,
+    closed: bool,    // a think block just ended, the newlines following it are dropped
+    pending: String, // what may be the beginning of a tag
+}
+
+impl ThinkSplitter {
+    // the reasoning and the answer parts of a delta
+    pub fn push(&mut self, delta: &str) -> (String, String) {
+        let mut buf = std::mem::take(&mut self.pending) + delta;
+        let (mut thought, mut answer) = (String::new(), String::new());
+        loop {
+            let tag = if self.thinking { "</think>" } else { "<think>" };
+            // hold back a trailing `<thi` until the next delta tells whether it's a tag
+            let (text, rest) = match buf.find(tag) {
+                Some(i) => (&buf[..i], Some(&buf[i + tag.len()..])),
+                None => {
+                    let keep = (1..tag.len().min(buf.len() + 1))
+                        .rev()
+                        .find(|&n| {
+                            let start = buf.len() - n;
+                            buf.is_char_boundary(start) && tag.starts_with(&buf[start..])
+                        })
+                        .unwrap_or(0);
+                    self.pending = buf[buf.len() - keep..].to_string();
+                    (&buf[..buf.len() - keep], None)
+                }
+            };
+            if self.thinking {
+                thought.push_str(text);
+            } else {
+                let text = if self.closed {
+                    text.trim_start_matches('\n')
+                } else {
+                    text
+                };
+                self.closed &= text.is_empty();
+                answer.push_str(text);
+            }
+            let Some(rest) = rest else {
+                break;
+            };
+            buf = rest.to_string();
+            self.closed = self.thinking;
+            self.thinking = !self.thinking;
+        }
+        (thought, answer)
+    }
+
+    // what was held back, once the stream is over
+    pub fn finish(&mut self) -> (String, String) {
+        let rest = std::mem::take(&mut self.pending);
+        if self.thinking {
+            (rest, String::new())
+        } else {
+            (String::new(), rest)
+        }
+    }
+}
+
+impl App {
+    // print a markdown text, the prose through the skin and the code blocks verbatim (or as plain
+    // text with --strip-markdown)
+    pub fn print_markdown(&self, md: &str) {
+        if self.strip_markdown {
+            print!("{}", strip_markdown(md));
+            let _ = stdout().flush();
+            return;
+        }
+        for segment in split_code_blocks(md) {
+            match segment {
+                Segment::Prose(prose) => {
+                    let width = termimad::terminal_size().0 as usize;
+                    let prose = tables(&inline_math(&prose), self.table_fit, width);
+                    if self.hyperlinks {
+                        let (prose, urls) = mark_links(&prose);
+                        let text = self.skin.term_text(&prose).to_string();
+                        print!("{}", hyperlinks(&text, &urls));
+                    } else {
+                        self.skin.print_text(&spell_out_links(&prose))
+                    }
+                }
+                Segment::Code(lines) => {
+                    let width = lines.iter().map(|l| l.width()).max().unwrap_or(0);
+                    let style = &self.skin.code_block.compound_style;
+                    for line in lines {
+                        let pad = width - line.width();
+                        println!("{}", style.apply_to(format!("{line}{}", " ".repeat(pad))));
+                    }
+                }
+            }
+        }
+        let _ = stdout().flush();
+    }
+
+    // a line diff of two responses, the removed lines in red and the added ones in green
+    pub fn print_diff(&self, old: &str, new: &str) {
+        let diff = TextDiff::from_lines(old, new);
+        for change in diff.iter_all_changes() {
+            let line = change.value().trim_end_matches('\n');
+            match change.tag() {
+                ChangeTag::Delete => println!("{}", format!("- {line}").red()),
+                ChangeTag::Insert => println!("{}", format!("+ {line}").green()),
+                ChangeTag::Equal => println!("{}", format!("  {line}").dim()),
+            }
+        }
+        let _ = stdout().flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a response cut inside its emoji sequences and between the chars of its CJK words
+    const DELTAS: [&str; 7] = [
+        "Hi 👍",
+        "\u{1f3fd}, ",
+        "👨\u{200d}",
+        "👩\u{200d}👧 你",
+        "好",
+        "世界",
+        " 😀!",
+    ];
+
+    #[test]
+    fn multibyte_deltas_add_up() {
+        let mut answer = String::new();
+        let mut shown = String::new();
+        let mut wrap = StreamWrap::new(80);
+        for delta in DELTAS {
+            answer.push_str(delta);
+            shown.push_str(&wrap.push(delta));
+        }
+        shown.push_str(&wrap.finish());
+        assert_eq!(answer, DELTAS.concat());
+        assert_eq!(shown, answer);
+        assert_eq!(rows(&shown, 80), 1);
+    }
+
+    #[test]
+    fn multibyte_deltas_wrap_in_columns() {
+        let deltas = ["你好", "世", "界 😀", "😀 ", "中文"];
+        let mut wrap = StreamWrap::new(10);
+        let mut shown: String = deltas.iter().map(|delta| wrap.push(delta)).collect();
+        shown.push_str(&wrap.finish());
+        // 9 columns on the first row, the emoji would take it to 13
+        assert_eq!(shown, "你好世界 \n😀😀 中文");
+        assert_eq!(shown.replace('\n', ""), deltas.concat());
+        assert_eq!(rows(&shown, 10), 2);
+        assert_eq!(rows(&deltas.concat(), 10), 2);
+    }
+
+    #[test]
+    fn wide_chars_at_the_end_of_a_row() {
+        // the 4th column is left empty, 中 doesn't fit in it
+        assert_eq!(rows("abc中", 4), 2);
+        assert_eq!(rows("ab中", 4), 1);
+        assert_eq!(rows("中中中中", 7), 2);
+        // the last 中 of the word goes to the second row, where `abc` no longer fits after it
+        let mut wrap = StreamWrap::new(5);
+        let shown = wrap.push("中中中 abc") + &wrap.finish();
+        assert_eq!(shown, "中中中 \nabc");
+        assert_eq!(rows(&shown, 5), 3);
+    }
+
+    #[test]
+    fn rows_skip_the_escape_codes() {
+        assert_eq!(rows("\x1b[1mabcd\x1b[0m", 4), 1);
+        assert_eq!(rows("\x1b[2m你好\x1b[0m\n\x1b[38;5;239m世界\x1b[39m", 4), 2);
+        assert_eq!(rows("\x1b[1mabcde\x1b[0m", 4), 2);
+    }
+
+    // the rows cleared before rendering a response cut off mid-line: the cursor is on the last
+    // row, `rows - 1` above it is where the response began
+    #[test]
+    fn rows_of_a_partial_response() {
+        assert_eq!(rows("", 80), 1);
+        assert_eq!(rows("partial", 80), 1);
+        assert_eq!(rows("a line\npartial", 80), 2);
+        assert_eq!(rows("```rust\nfn main() {", 80), 2);
+        // a full row leaves the cursor on it until the next char is written
+        assert_eq!(rows("abcd", 4), 1);
+        assert_eq!(rows("abcdef", 4), 2);
+        // a line break puts it on a row of its own
+        assert_eq!(rows("done\n", 80), 2);
+    }
+
+    #[test]
+    fn think_tags_split_across_deltas() {
+        let mut think = ThinkSplitter::default();
+        let (mut thought, mut answer) = (String::new(), String::new());
+        let deltas = [
+            "Hel",
+            "lo <th",
+            "ink>rea",
+            "son</thi",
+            "nk>\n\nAns",
+            "wer <",
+            "b>",
+        ];
+        for delta in deltas {
+            let (t, a) = think.push(delta);
+            thought.push_str(&t);
+            answer.push_str(&a);
+        }
+        let (t, a) = think.finish();
+        assert_eq!(thought + &t, "reason");
+        assert_eq!(answer + &a, "Hello Answer <b>");
+    }
+
+    #[test]
+    fn think_tags_next_to_multibyte_chars() {
+        let mut think = ThinkSplitter::default();
+        assert_eq!(think.push("思<"), (String::new(), "思".to_string()));
+        assert_eq!(think.push("think>考"), ("考".to_string(), String::new()));
+        assert_eq!(think.push("え</"), ("え".to_string(), String::new()));
+        assert_eq!(
+            think.push("think>答え"),
+            (String::new(), "答え".to_string())
+        );
+        assert_eq!(think.push("。<thi"), (String::new(), "。".to_string()));
+        assert_eq!(think.finish(), (String::new(), "<thi".to_string()));
+    }
+}
@@ -0,0 +1,158 @@
+use futures::StreamExt;
+use std::{collections::VecDeque, io, sync::atomic::Ordering};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::{api, App};
+
+// a tiny local http endpoint: POST a prompt (plain text, or json `{"prompt": "..."}`) and the
+// response streams back as server-sent events, one `data:` line (a json string) per delta,
+// ended by `data: [DONE]`. requests are served one at a time, each on a fresh history: those
+// coming in while a response is streaming are turned down with a 503, and the rest wait their turn
+//
+//  curl -N -d 'hi there' http://127.0.0.1:8080/
+impl App {
+    pub async fn serve(&mut self, addr: &str) {
+        let listener = match TcpListener::bind(addr).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                eprintln!("failed to listen on {addr}: {e}");
+                return;
+            }
+        };
+        eprintln!("serving on http://{addr}");
+        self.serve_on(listener).await
+    }
+
+    async fn serve_on(&mut self, listener: TcpListener) {
+        let in_flight = self.in_flight.clone();
+        let mut waiting = VecDeque::new();
+        loop {
+            let socket = match waiting.pop_front() {
+                Some(socket) => socket,
+                None => match listener.accept().await {
+                    Ok((socket, _)) => socket,
+                    Err(e) => {
+                        eprintln!("failed to accept a connection: {e}");
+                        continue;
+                    }
+                },
+            };
+            let serving = self.serve_one(socket);
+            tokio::pin!(serving);
+            let result = loop {
+                tokio::select! {
+                    result = &mut serving => break result,
+                    conn = listener.accept() => match conn {
+                        Ok((socket, _)) if in_flight.load(Ordering::SeqCst) => {
+                            tokio::spawn(reject(socket));
+                        }
+                        Ok((socket, _)) => waiting.push_back(socket),
+                        Err(e) => eprintln!("failed to accept a connection: {e}"),
+                    },
+                }
+            };
+            if let Err(e) = result {
+                eprintln!("request failed: {e}");
+            }
+        }
+    }
+
+    async fn serve_one(&mut self, socket: TcpStream) -> io::Result<()> {
+        let mut reader = BufReader::new(socket);
+        let Some(pmt) = read_request(&mut reader).await? else {
+            let resp =
+                "HTTP/1.1 405 Method Not Allowed\r\nAllow: POST\r\nContent-Length: 0\r\n\r\n";
+            return reader.get_mut().write_all(resp.as_bytes()).await;
+        };
+        let socket = reader.get_mut();
+        socket
+            .write_all(
+                b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n",
+            )
+            .await?;
+
+        let history = self.history.clone();
+        let mut stream = match self.send_message(pmt).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                self.history = history;
+                return write_event(socket, "error", &api::describe_error(&e)).await;
+            }
+        };
+        while let Some(result) = stream.next().await {
+            match result {
+                Ok(resp) => {
+                    for chat_choice in resp.choices {
+                        if let Some(content) = chat_choice.delta.content {
+                            write_event(socket, "message", &content).await?;
+                        }
+                    }
+                }
+                Err(e) => write_event(socket, "error", &api::describe_error(&e)).await?,
+            }
+        }
+        self.history = history;
+        socket.write_all(b"data: [DONE]\n\n").await?;
+        socket.shutdown().await
+    }
+}
+
+// the prompt of a POST request, None for any other method
+async fn read_request(reader: &mut BufReader<TcpStream>) -> io::Result<Option<String>> {
+    let mut line = String::new();
+    reader.read_line(&mut line).await?;
+    let is_post = line.starts_with("POST ");
+
+    let mut content_length = 0;
+    loop {
+        line.clear();
+        if reader.read_line(&mut line).await? == 0 || line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().unwrap_or(0);
+            }
+        }
+    }
+    if !is_post {
+        return Ok(None);
+    }
+
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body).await?;
+    let body = String::from_utf8_lossy(&body).to_string();
+    let pmt = serde_json::from_str::<serde_json::Value>(&body)
+        .ok()
+        .and_then(|json| json["prompt"].as_str().map(String::from))
+        .unwrap_or(body);
+    Ok(Some(pmt))
+}
+
+// turn down a request that came in while a response is streaming
+async fn reject(socket: TcpStream) -> io::Result<()> {
+    let mut reader = BufReader::new(socket);
+    read_request(&mut reader).await?;
+    let body = "a request is already in flight, try again once it's answered\n";
+    let resp = format!(
+        "HTTP/1.1 503 Service Unavailable\r\nRetry-After: 1\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    let socket = reader.get_mut();
+    socket.write_all(resp.as_bytes()).await?;
+    socket.shutdown().await
+}
+
+async fn write_event(socket: &mut TcpStream, event: &str, data: &str) -> io::Result<()> {
+    let data = serde_json::to_string(data)?;
+    let event = if event == "message" {
+        format!("data: {data}\n\n")
+    } else {
+        format!("event: {event}\ndata: {data}\n\n")
+    };
+    socket.write_all(event.as_bytes()).await?;
+    socket.flush().await
+}
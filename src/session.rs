@@ -0,0 +1,461 @@
+use async_openai::types::{
+    ChatCompletionRequestAssistantMessageArgs, ChatCompletionRequestMessage,
+    ChatCompletionRequestSystemMessageArgs, ChatCompletionRequestUserMessageArgs,
+};
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use crate::{message_role, message_text, paths, render, RoleTag};
+
+// one history message as it's stored on disk. the sdk's message enum is untagged, so it can't
+// be reliably deserialized back by itself
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SavedMessage {
+    pub role: String,
+    pub content: String,
+}
+
+// the version of the session files written by this build, bumped when their format changes
+pub const VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Session {
+    // 0 for the files saved before sessions had a version
+    #[serde(default)]
+    pub version: u32,
+    pub messages: Vec<SavedMessage>,
+    // the session this one was forked from with `/branch`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent: Option<String>,
+}
+
+impl Session {
+    pub fn from_history(history: &[ChatCompletionRequestMessage]) -> Self {
+        let messages = history
+            .iter()
+            .map(|message| SavedMessage {
+                role: message_role(message).to_string(),
+                content: message_text(message).to_string(),
+            })
+            .collect();
+        Self {
+            version: VERSION,
+            messages,
+            parent: None,
+        }
+    }
+
+    // saved by a newer build, only loaded best-effort
+    pub fn is_newer(&self) -> bool {
+        self.version > VERSION
+    }
+
+    pub fn to_history(&self) -> Vec<ChatCompletionRequestMessage> {
+        self.messages
+            .iter()
+            .filter_map(SavedMessage::to_message)
+            .collect()
+    }
+
+    // older sessions are migrated to the current version, the newer ones are loaded as far as
+    // they're understood (the fields this build doesn't know are dropped) and keep their version
+    pub fn load(path: &PathBuf) -> io::Result<Self> {
+        let json = fs::read_to_string(path)?;
+        let mut value: serde_json::Value = serde_json::from_str(&json)?;
+        let version = version_of(&value);
+        if version < VERSION {
+            migrate(&mut value, version);
+        }
+        Ok(serde_json::from_value(value)?)
+    }
+
+    // names of the saved sessions, sorted
+    pub fn list() -> io::Result<Vec<String>> {
+        let mut names = Vec::new();
+        for entry in fs::read_dir(paths::sessions_dir())? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "json") {
+                if let Some(name) = path.file_stem().and_then(|stem| stem.to_str()) {
+                    names.push(name.to_string());
+                }
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    // drop the oldest turns until the serialized session fits in `max_bytes`, the system
+    // messages are kept. returns how many messages were dropped
+    pub fn trim_to(&mut self, max_bytes: usize) -> usize {
+        // the session is serialized once, each dropped message takes its own size off
+        let mut size = serde_json::to_vec_pretty(self).map_or(0, |json| json.len());
+        if size <= max_bytes {
+            return 0;
+        }
+        let mut dropped = vec![false; self.messages.len()];
+        let mut next = 0; // the oldest message that may still be dropped
+        while size > max_bytes {
+            let Some(oldest) =
+                (next..self.messages.len()).find(|&i| self.messages[i].role != "system")
+            else {
+                break;
+            };
+            // don't leave the replies of a dropped question behind
+            let mut end = oldest + 1;
+            while end < self.messages.len()
+                && self.messages[end].role != "user"
+                && self.messages[end].role != "system"
+            {
+                end += 1;
+            }
+            for (flag, message) in dropped[oldest..end]
+                .iter_mut()
+                .zip(&self.messages[oldest..end])
+            {
+                *flag = true;
+                size = size.saturating_sub(message.serialized_size());
+            }
+            next = end;
+        }
+        let before = self.messages.len();
+        let mut dropped = dropped.into_iter();
+        self.messages.retain(|_| !dropped.next().unwrap_or(false));
+        before - self.messages.len()
+    }
+
+    // write the session as json, through a temp file and a rename so an interrupted write
+    // never leaves a half written session behind
+    pub fn save(&self, path: &PathBuf) -> io::Result<()> {
+        // a newer build's session would lose what this one doesn't understand
+        let saved = fs::read_to_string(path)
+            .ok()
+            .and_then(|json| serde_json::from_str(&json).ok())
+            .map_or(0, |value| version_of(&value));
+        if saved > VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!(
+                    "it was saved by a newer version of cli-gpt (session version {saved}, this one \
+                     supports up to {VERSION}), not overwriting it"
+                ),
+            ));
+        }
+        if let Some(dir) = path.parent() {
+            paths::create_dir(dir)?;
+        }
+        let json = serde_json::to_string_pretty(self)?;
+        let tmp = path.with_extension("json.tmp");
+        fs::write(&tmp, json)?;
+        fs::rename(&tmp, path)
+    }
+}
+
+fn version_of(value: &serde_json::Value) -> u32 {
+    value["version"]
+        .as_u64()
+        .map_or(0, |version| version.try_into().unwrap_or(u32::MAX))
+}
+
+// bring a session saved with an older version up to the current one, a step per version
+fn migrate(value: &mut serde_json::Value, from: u32) {
+    for version in from..VERSION {
+        match version {
+            // the unversioned files have the same fields as version 1
+            0 => {}
+            _ => unreachable!("no migration from session version {version}"),
+        }
+    }
+    value["version"] = VERSION.into();
+}
+
+// the session changes the auto-save didn't write yet, saved when the process is terminated
+static PENDING: Mutex<Option<(PathBuf, Session)>> = Mutex::new(None);
+
+pub fn set_pending(path: PathBuf, session: Session) {
+    if let Ok(mut pending) = PENDING.lock() {
+        *pending = Some((path, session));
+    }
+}
+
+// the session was saved, there's nothing pending anymore
+pub fn set_pending_saved() {
+    if let Ok(mut pending) = PENDING.lock() {
+        *pending = None;
+    }
+}
+
+pub fn save_pending() {
+    let Some((path, session)) = PENDING.lock().ok().and_then(|mut pending| pending.take()) else {
+        return;
+    };
+    if let Err(e) = session.save(&path) {
+        eprintln!("failed to save the session to {}: {e}", path.display());
+    }
+}
+
+// the headings of the messages in a markdown transcript, by role
+const HEADINGS: [(&str, &str); 3] = [
+    ("system", "## System"),
+    ("user", "## You"),
+    ("assistant", "## Assistant"),
+];
+
+// the history as a transcript, each message tagged with its role in the `tag` style:
+//
+//  heading: markdown, the message under `## You`, `## Assistant` or `## System`
+//  xml:     the message between `<user>` and `</user>` lines (or assistant, system)
+//  chatml:  the message between `<|im_start|>user` and `<|im_end|>`
+//
+// the messages are written as they are, so `parse_transcript` gives them back
+pub fn to_transcript(history: &[ChatCompletionRequestMessage], tag: RoleTag) -> String {
+    if tag == RoleTag::Heading {
+        return to_markdown(history);
+    }
+    let mut out = String::new();
+    for message in history {
+        let role = message_role(message);
+        if !HEADINGS.iter().any(|(r, _)| *r == role) {
+            continue;
+        }
+        let text = render::strip_ansi(message_text(message));
+        let text = text.trim();
+        match tag {
+            RoleTag::Xml => out.push_str(&format!("<{role}>\n{text}\n</{role}>\n\n")),
+            _ => out.push_str(&format!("<|im_start|>{role}\n{text}<|im_end|>\n")),
+        }
+    }
+    out
+}
+
+// the history as a markdown transcript, each message under a heading telling its role
+pub fn to_markdown(history: &[ChatCompletionRequestMessage]) -> String {
+    let mut md = String::new();
+    for message in history {
+        let role = message_role(message);
+        let Some((_, heading)) = HEADINGS.iter().find(|(r, _)| *r == role) else {
+            continue;
+        };
+        let text = render::strip_ansi(message_text(message));
+        md.push_str(&format!("{heading}\n\n{}\n\n", text.trim()));
+    }
+    md
+}
+
+// write the history as a transcript to `path`, the system prompt only when `system`
+pub fn export(
+    history: &[ChatCompletionRequestMessage],
+    path: &Path,
+    system: bool,
+    tag: RoleTag,
+) -> io::Result<()> {
+    let history: Vec<_> = history
+        .iter()
+        .filter(|m| system || !matches!(m, ChatCompletionRequestMessage::System(_)))
+        .cloned()
+        .collect();
+    fs::write(path, to_transcript(&history, tag))
+}
+
+// export every saved session to `<dir>/<name>.md` (`.txt` for the tags other than headings),
+// the directory is created when missing. returns how many were written
+pub fn export_all(dir: &Path, system: bool, tag: RoleTag) -> Result<usize, String> {
+    fs::create_dir_all(dir).map_err(|e| format!("{}: {e}", dir.display()))?;
+    let names = Session::list().map_err(|e| format!("{}: {e}", paths::sessions_dir().display()))?;
+    for name in names.iter() {
+        let path = paths::session_file(name);
+        let session = Session::load(&path).map_err(|e| format!("{}: {e}", path.display()))?;
+        let extension = if tag == RoleTag::Heading { "md" } else { "txt" };
+        let target = dir.join(format!("{name}.{extension}"));
+        export(&session.to_history(), &target, system, tag)
+            .map_err(|e| format!("{}: {e}", target.display()))?;
+    }
+    Ok(names.len())
+}
+
+// parse a transcript written by `to_transcript` back into messages, whatever its tag style,
+// told by how it starts
+pub fn parse_transcript(text: &str) -> Result<Vec<SavedMessage>, String> {
+    let first = text.trim_start().lines().next().unwrap_or("");
+    if first.starts_with("<|im_start|>") {
+        parse_chatml(text)
+    } else if HEADINGS
+        .iter()
+        .any(|(role, _)| first == format!("<{role}>"))
+    {
+        parse_xml(text)
+    } else {
+        parse_markdown(text)
+    }
+}
+
+// `<|im_start|>role` lines, each message ending with `<|im_end|>`
+fn parse_chatml(text: &str) -> Result<Vec<SavedMessage>, String> {
+    let mut messages = Vec::new();
+    for (i, chunk) in text.split("<|im_start|>").skip(1).enumerate() {
+        let (role, rest) = chunk.split_once('\n').unwrap_or((chunk, ""));
+        let role = role.trim();
+        if !HEADINGS.iter().any(|(r, _)| *r == role) {
+            return Err(format!("message {}: unknown role {role:?}", i + 1));
+        }
+        let Some((content, _)) = rest.rsplit_once("<|im_end|>") else {
+            return Err(format!("message {}: no closing <|im_end|>", i + 1));
+        };
+        messages.push(SavedMessage {
+            role: role.to_string(),
+            content: content.to_string(),
+        });
+    }
+    Ok(messages)
+}
+
+// `<role>` and `</role>` lines around each message. a closing tag only ends the message when
+// it's followed by the next opening one (or the end), so a message may quote the tags
+fn parse_xml(text: &str) -> Result<Vec<SavedMessage>, String> {
+    let mut messages = Vec::new();
+    let mut current: Option<(&str, usize, Vec<&str>)> = None; // the role, its line and the lines
+    let opening = |line: &str| {
+        HEADINGS
+            .iter()
+            .map(|(role, _)| *role)
+            .find(|role| line.trim_end() == format!("<{role}>"))
+    };
+    // the content of a message whose last line is its closing tag, blank lines aside
+    let closed = |role: &str, lines: &[&str]| {
+        let end = lines.iter().rposition(|line| !line.trim().is_empty())?;
+        (lines[end].trim_end() == format!("</{role}>")).then(|| lines[..end].join("\n"))
+    };
+    for (i, line) in text.lines().enumerate() {
+        match (&mut current, opening(line)) {
+            (None, Some(role)) => current = Some((role, i + 1, Vec::new())),
+            (None, None) if line.trim().is_empty() => {}
+            (None, None) => return Err(format!("line {}: expected a <role> tag", i + 1)),
+            (Some((role, _, lines)), Some(next)) => match closed(role, lines) {
+                Some(content) => {
+                    messages.push(SavedMessage {
+                        role: role.to_string(),
+                        content,
+                    });
+                    current = Some((next, i + 1, Vec::new()));
+                }
+                None => lines.push(line),
+            },
+            (Some((_, _, lines)), None) => lines.push(line),
+        }
+    }
+    if let Some((role, line, lines)) = current {
+        let Some(content) = closed(role, &lines) else {
+            return Err(format!("line {line}: <{role}> is never closed"));
+        };
+        messages.push(SavedMessage {
+            role: role.to_string(),
+            content,
+        });
+    }
+    Ok(messages)
+}
+
+// parse a transcript written by `to_markdown` (and maybe edited by hand) back into messages.
+// the headings inside fenced code are part of the message, a `# title` may come first
+pub fn parse_markdown(md: &str) -> Result<Vec<SavedMessage>, String> {
+    let mut messages: Vec<(usize, SavedMessage)> = Vec::new(); // with the line of the heading
+    let mut fence: Option<String> = None;
+    for (i, line) in md.lines().enumerate() {
+        let trimmed = line.trim_start();
+        if let Some(open) = &fence {
+            if trimmed.trim_end() == open {
+                fence = None;
+            }
+        } else if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+            let c = trimmed.chars().next().unwrap();
+            fence = Some(trimmed.chars().take_while(|&x| x == c).collect());
+        } else if let Some((role, _)) = HEADINGS.iter().find(|(_, h)| line.trim_end() == *h) {
+            messages.push((
+                i + 1,
+                SavedMessage {
+                    role: role.to_string(),
+                    content: String::new(),
+                },
+            ));
+            continue;
+        }
+        match messages.last_mut() {
+            Some((_, message)) => {
+                message.content.push_str(line);
+                message.content.push('\n');
+            }
+            None if line.trim().is_empty() || (line.starts_with("# ") && i == 0) => {}
+            None => {
+                return Err(format!(
+                    "line {}: expected a `## You`, `## Assistant` or `## System` heading",
+                    i + 1
+                ))
+            }
+        }
+    }
+    messages
+        .into_iter()
+        .map(|(line, mut message)| {
+            message.content = message.content.trim().to_string();
+            if message.content.is_empty() {
+                return Err(format!("line {line}: the message is empty"));
+            }
+            Ok(message)
+        })
+        .collect()
+}
+
+// a pre-built conversation (eg: few-shot examples), a json array of `{role, content}`
+// messages. unlike a session, a message with an unknown role is an error
+pub fn load_messages(path: &PathBuf) -> Result<Vec<ChatCompletionRequestMessage>, String> {
+    let json = fs::read_to_string(path).map_err(|e| format!("{}: {e}", path.display()))?;
+    let messages: Vec<SavedMessage> =
+        serde_json::from_str(&json).map_err(|e| format!("{}: {e}", path.display()))?;
+    messages
+        .iter()
+        .enumerate()
+        .map(|(i, message)| {
+            message.to_message().ok_or_else(|| {
+                format!(
+                    "{}: message {i} has the role {:?}, expected system, developer, user or assistant",
+                    path.display(),
+                    message.role
+                )
+            })
+        })
+        .collect()
+}
+
+impl SavedMessage {
+    // the bytes it takes in a pretty printed session: its 4 lines indented by 4 more spaces in
+    // the messages array, and the separator after it
+    fn serialized_size(&self) -> usize {
+        serde_json::to_vec_pretty(self).map_or(0, |json| json.len()) + 4 * 4 + 2
+    }
+
+    pub fn to_message(&self) -> Option<ChatCompletionRequestMessage> {
+        let content = self.content.clone();
+        match self.role.as_str() {
+            // the role the instructions are sent with is picked per request
+            "system" | "developer" => ChatCompletionRequestSystemMessageArgs::default()
+                .content(content)
+                .build()
+                .ok()
+                .map(Into::into),
+            "user" => ChatCompletionRequestUserMessageArgs::default()
+                .content(content)
+                .build()
+                .ok()
+                .map(Into::into),
+            "assistant" => ChatCompletionRequestAssistantMessageArgs::default()
+                .content(content)
+                .build()
+                .ok()
+                .map(Into::into),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,485 @@
+use async_openai::config::OPENAI_API_BASE;
+use serde_derive::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fs, path::Path};
+
+use crate::{paths, ReasoningEffort};
+
+pub const DEFAULT_PROFILE: &str = "default";
+pub const DEFAULT_MODEL: &str = "gpt-3.5-turbo";
+pub const DEFAULT_PROMPT: &str = "» ";
+pub const DEFAULT_GREETING: &str = "Hello! How can I assist you today?";
+pub const DEFAULT_WAITING: &str = "*thinking…*";
+pub const DEFAULT_ALIAS_SIGIL: &str = ";;";
+// the models `/model` cycles through when none are configured, a cheap and a powerful one
+pub const DEFAULT_FAVORITE_MODELS: [&str; 2] = ["gpt-4o-mini", "gpt-4o"];
+
+// settings of a profile, whatever is left out falls back to the env vars and the defaults
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(default)]
+pub struct Settings {
+    pub api_key: Option<String>,
+    pub api_base: Option<String>,
+    pub model: Option<String>,
+    pub org_id: Option<String>,
+    pub project_id: Option<String>,
+    pub favorite_models: Option<Vec<String>>,
+    pub prompt: Option<String>,   // drawn before the input
+    pub greeting: Option<String>, // printed when there's no initial prompt, "" for none
+    pub waiting: Option<String>,  // shown until the response starts, markdown styled by the skin
+    pub prepend: Option<String>,  // put before every prompt sent, eg: "In French:"
+    pub append: Option<String>,   // put after every prompt sent, eg: "Answer concisely."
+    // whether the system prompt is written to the markdown exports (not by default, they're
+    // meant to be shared) and to the session files (by default, to be resumed as it was)
+    pub export_system: Option<bool>,
+    pub save_system: Option<bool>,
+    // sent to the api, eg: for the gateways telling the clients apart, "cli-gpt/<version>" by
+    // default
+    pub user_agent: Option<String>,
+    // short names expanded in the prompts when written after the sigil, eg: `;;tldr`
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub aliases: BTreeMap<String, String>,
+    pub alias_sigil: Option<String>, // ";;" by default
+    pub theme: Option<Theme>,
+    // default parameters of the models, applied whenever they're in use
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub models: BTreeMap<String, ModelParams>,
+}
+
+// parameters a model is sent with unless the flags say otherwise
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(default)]
+pub struct ModelParams {
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub max_tokens: Option<u16>,
+    pub reasoning_effort: Option<ReasoningEffort>, // for the reasoning models (o1, o3...)
+    // used when the conversation has no system prompt (eg: from a persona) of its own
+    pub system_prompt: Option<String>,
+}
+
+// the styling of the rendered markdown: a preset and the tweaks over it. the colors are names
+// (eg: "red", "dark_cyan"), "#rrggbb" or ansi numbers (0-255)
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(default)]
+pub struct Theme {
+    pub preset: Option<String>, // "default", "plain" (no colors) or "light" (light terminals)
+    pub text: Option<String>,
+    pub headers: Option<String>,
+    pub bold: Option<String>,
+    pub italic: Option<String>,
+    pub code: Option<String>,     // the inline code and the code blocks
+    pub bullet: Option<char>,     // starting the list items, "•" by default
+    pub quote_mark: Option<char>, // in front of the quoted lines, "▐" by default
+}
+
+// the config file, holding named profiles, eg:
+//
+//  [profiles.default]
+//  model = "gpt-4-1106-preview"
+//  favorite_models = ["gpt-4o-mini", "gpt-4o"]
+//  prompt = "you> "
+//  greeting = "What now?"
+//  waiting = "**…**"
+//  append = "Answer concisely."
+//
+//  [profiles.default.aliases]
+//  tldr = "Summarize in 3 bullet points."
+//
+//  [profiles.default.theme]
+//  preset = "light"
+//  bullet = "-"
+//
+//  [profiles.default.models.gpt-4o]
+//  temperature = 1.2
+//  system_prompt = "You are a creative writer."
+//
+//  [profiles.default.models.o3-mini]
+//  reasoning_effort = "high"
+//
+//  [profiles.work]
+//  api_base = "https://llm-proxy.example.com/v1"
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct ConfigFile {
+    pub profiles: BTreeMap<String, Settings>,
+}
+
+impl Default for ConfigFile {
+    fn default() -> Self {
+        let mut profiles = BTreeMap::new();
+        profiles.insert(DEFAULT_PROFILE.to_string(), Settings::default());
+        Self { profiles }
+    }
+}
+
+// load the settings of `profile` from the config file, it's created on the first run
+pub fn load_settings(profile: &str) -> Result<Settings, String> {
+    let path = paths::config_file();
+    if let Some(dir) = path.parent() {
+        paths::create_dir(dir).map_err(|e| format!("{}: {e}", dir.display()))?;
+    }
+    let config: ConfigFile = confy::load_path(&path).map_err(|e| e.to_string())?;
+    // it may hold the api key
+    let _ = paths::restrict_file(&path);
+    let settings = match config.profiles.get(profile) {
+        Some(settings) => settings.clone(),
+        None if profile == DEFAULT_PROFILE => Settings::default(),
+        None => return Err(format!("no profile named {profile} in {}", path.display())),
+    };
+    let settings = expand_env(settings).map_err(|e| format!("{}: {e}", path.display()))?;
+    match paths::local_config_file() {
+        Some(path) => Ok(load_local(&path)?.over(settings)),
+        None => Ok(settings),
+    }
+}
+
+// the settings with the `${VAR}` in their values replaced by the env vars, eg: to share a config
+// without its secrets. `$$` is a literal `$`, a `$` followed by anything else is left as is.
+// only for the global config, a project's one doesn't get to read the env
+fn expand_env(settings: Settings) -> Result<Settings, String> {
+    let mut value = toml::Value::try_from(settings).map_err(|e| e.to_string())?;
+    expand_value(&mut value, "")?;
+    value.try_into().map_err(|e| e.to_string())
+}
+
+fn expand_value(value: &mut toml::Value, key: &str) -> Result<(), String> {
+    match value {
+        toml::Value::String(text) => {
+            *text = expand_vars(text).map_err(|e| format!("{key}: {e}"))?
+        }
+        toml::Value::Array(items) => {
+            for item in items {
+                expand_value(item, key)?;
+            }
+        }
+        toml::Value::Table(table) => {
+            for (name, item) in table.iter_mut() {
+                let key = if key.is_empty() {
+                    name.clone()
+                } else {
+                    format!("{key}.{name}")
+                };
+                expand_value(item, &key)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn expand_vars(text: &str) -> Result<String, String> {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(i) = rest.find('$') {
+        out.push_str(&rest[..i]);
+        let after = &rest[i + 1..];
+        if let Some(after) = after.strip_prefix('$') {
+            out.push('$');
+            rest = after;
+        } else if let Some(braced) = after.strip_prefix('{') {
+            let Some(end) = braced.find('}') else {
+                return Err("a `${` is never closed, write `$$` for a literal `$`".to_string());
+            };
+            let name = &braced[..end];
+            let value = std::env::var(name)
+                .map_err(|_| format!("the environment variable {name} is not set"))?;
+            out.push_str(&value);
+            rest = &braced[end + 1..];
+        } else {
+            out.push('$');
+            rest = after;
+        }
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+// make `model` the model of `profile` in the config file. the key is edited in place, the
+// comments, the other keys and the `${VAR}` placeholders are left as they were written
+pub fn save_model(profile: &str, model: &str) -> Result<(), String> {
+    let path = paths::config_file();
+    let text = fs::read_to_string(&path).map_err(|e| format!("{}: {e}", path.display()))?;
+    let text = set_model(&text, profile, model).map_err(|e| format!("{}: {e}", path.display()))?;
+    fs::write(&path, text).map_err(|e| format!("{}: {e}", path.display()))
+}
+
+fn set_model(text: &str, profile: &str, model: &str) -> Result<String, String> {
+    let mut doc: toml_edit::DocumentMut = text.parse().map_err(|e| format!("{e}"))?;
+    let profiles = doc
+        .entry("profiles")
+        .or_insert_with(|| {
+            // no bare `[profiles]` header above the profile
+            let mut table = toml_edit::Table::new();
+            table.set_implicit(true);
+            toml_edit::Item::Table(table)
+        })
+        .as_table_like_mut()
+        .ok_or("`profiles` isn't a table")?;
+    let settings = profiles
+        .entry(profile)
+        .or_insert(toml_edit::table())
+        .as_table_like_mut()
+        .ok_or_else(|| format!("the profile {profile} isn't a table"))?;
+    match settings
+        .get_mut("model")
+        .and_then(|item| item.as_value_mut())
+    {
+        // keeping the comment after it
+        Some(value) => {
+            let decor = value.decor().clone();
+            *value = model.into();
+            *value.decor_mut() = decor;
+        }
+        None => {
+            settings.insert("model", toml_edit::value(model));
+        }
+    }
+    Ok(doc.to_string())
+}
+
+// the settings of a project's `.cli-gpt.toml`, a profile by itself. it comes with the code
+// that's checked out, so it can't pick where the api key is sent
+fn load_local(path: &Path) -> Result<Settings, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("{}: {e}", path.display()))?;
+    let mut local: Settings =
+        toml::from_str(&text).map_err(|e| format!("{}: {e}", path.display()))?;
+    let ignored: Vec<&str> = [
+        ("api_key", local.api_key.take().is_some()),
+        ("api_base", local.api_base.take().is_some()),
+        ("org_id", local.org_id.take().is_some()),
+        ("project_id", local.project_id.take().is_some()),
+    ]
+    .into_iter()
+    .filter_map(|(key, set)| set.then_some(key))
+    .collect();
+    if !ignored.is_empty() {
+        eprintln!(
+            "{}: ignoring {}, they're only read from the global config",
+            path.display(),
+            ignored.join(", ")
+        );
+    }
+    Ok(local)
+}
+
+impl Settings {
+    // these settings, falling back to `base` for what they leave out
+    fn over(self, base: Settings) -> Settings {
+        let mut aliases = base.aliases;
+        aliases.extend(self.aliases);
+        let mut models = base.models;
+        for (name, params) in self.models {
+            let base = models.remove(&name).unwrap_or_default();
+            models.insert(name, params.over(base));
+        }
+        Settings {
+            api_key: self.api_key.or(base.api_key),
+            api_base: self.api_base.or(base.api_base),
+            model: self.model.or(base.model),
+            org_id: self.org_id.or(base.org_id),
+            project_id: self.project_id.or(base.project_id),
+            favorite_models: self.favorite_models.or(base.favorite_models),
+            prompt: self.prompt.or(base.prompt),
+            greeting: self.greeting.or(base.greeting),
+            waiting: self.waiting.or(base.waiting),
+            prepend: self.prepend.or(base.prepend),
+            append: self.append.or(base.append),
+            export_system: self.export_system.or(base.export_system),
+            save_system: self.save_system.or(base.save_system),
+            user_agent: self.user_agent.or(base.user_agent),
+            aliases,
+            alias_sigil: self.alias_sigil.or(base.alias_sigil),
+            theme: match (self.theme, base.theme) {
+                (Some(theme), Some(base)) => Some(theme.over(base)),
+                (theme, base) => theme.or(base),
+            },
+            models,
+        }
+    }
+}
+
+impl Theme {
+    fn over(self, base: Theme) -> Theme {
+        Theme {
+            preset: self.preset.or(base.preset),
+            text: self.text.or(base.text),
+            headers: self.headers.or(base.headers),
+            bold: self.bold.or(base.bold),
+            italic: self.italic.or(base.italic),
+            code: self.code.or(base.code),
+            bullet: self.bullet.or(base.bullet),
+            quote_mark: self.quote_mark.or(base.quote_mark),
+        }
+    }
+}
+
+impl ModelParams {
+    fn over(self, base: ModelParams) -> ModelParams {
+        ModelParams {
+            temperature: self.temperature.or(base.temperature),
+            top_p: self.top_p.or(base.top_p),
+            max_tokens: self.max_tokens.or(base.max_tokens),
+            reasoning_effort: self.reasoning_effort.or(base.reasoning_effort),
+            system_prompt: self.system_prompt.or(base.system_prompt),
+        }
+    }
+}
+
+// where an effective setting comes from
+#[derive(Debug, Clone, Copy)]
+pub enum Source {
+    Flag(&'static str),
+    Env(&'static str),
+    Config,
+    Default,
+}
+
+impl std::fmt::Display for Source {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Source::Flag(flag) => write!(f, "flag {flag}"),
+            Source::Env(var) => write!(f, "env {var}"),
+            Source::Config => write!(f, "config file"),
+            Source::Default => write!(f, "default"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Sourced<T> {
+    pub value: T,
+    pub source: Source,
+}
+
+fn sourced<T>(value: T, source: Source) -> Sourced<T> {
+    Sourced { value, source }
+}
+
+// the settings in effect once the flags, env vars, config file and defaults are merged, in
+// that order of precedence
+#[derive(Debug)]
+pub struct Effective {
+    pub profile: String,
+    pub api_key: Option<Sourced<String>>,
+    pub api_base: Sourced<String>,
+    pub model: Sourced<String>,
+    pub org_id: Option<Sourced<String>>,
+    pub project_id: Option<Sourced<String>>,
+    pub favorite_models: Sourced<Vec<String>>,
+    pub models: BTreeMap<String, ModelParams>,
+    pub prompt: String,
+    pub greeting: String,
+    pub waiting: String,
+    pub prepend: Option<String>,
+    pub append: Option<String>,
+    pub export_system: bool,
+    pub save_system: bool,
+    pub user_agent: String,
+    pub aliases: BTreeMap<String, String>,
+    pub alias_sigil: String,
+    pub theme: Theme,
+}
+
+impl Effective {
+    pub fn resolve(profile: &str, settings: Settings, model: Option<String>, gpt4: bool) -> Self {
+        let api_key = match std::env::var("OPENAI_API_KEY") {
+            Ok(key) => Some(sourced(key, Source::Env("OPENAI_API_KEY"))),
+            Err(_) => settings.api_key.map(|key| sourced(key, Source::Config)),
+        };
+        let api_base = match settings.api_base {
+            Some(api_base) => sourced(api_base, Source::Config),
+            None => sourced(OPENAI_API_BASE.to_string(), Source::Default),
+        };
+        let model = match (model, gpt4, settings.model) {
+            (Some(model), _, _) => sourced(model, Source::Flag("--model")),
+            (None, true, _) => sourced("gpt-4-1106-preview".to_string(), Source::Flag("-4")),
+            (None, false, Some(model)) => sourced(model, Source::Config),
+            (None, false, None) => sourced(DEFAULT_MODEL.to_string(), Source::Default),
+        };
+        let favorite_models = match settings.favorite_models {
+            Some(models) if !models.is_empty() => sourced(models, Source::Config),
+            _ => sourced(
+                DEFAULT_FAVORITE_MODELS.map(String::from).to_vec(),
+                Source::Default,
+            ),
+        };
+        Self {
+            profile: profile.to_string(),
+            api_key,
+            api_base,
+            model,
+            org_id: env_or_config("OPENAI_ORG_ID", settings.org_id),
+            project_id: env_or_config("OPENAI_PROJECT_ID", settings.project_id),
+            favorite_models,
+            models: settings.models,
+            prompt: settings
+                .prompt
+                .unwrap_or_else(|| DEFAULT_PROMPT.to_string()),
+            greeting: settings
+                .greeting
+                .unwrap_or_else(|| DEFAULT_GREETING.to_string()),
+            waiting: settings
+                .waiting
+                .unwrap_or_else(|| DEFAULT_WAITING.to_string()),
+            prepend: settings.prepend,
+            append: settings.append,
+            export_system: settings.export_system.unwrap_or(false),
+            save_system: settings.save_system.unwrap_or(true),
+            user_agent: settings
+                .user_agent
+                .unwrap_or_else(|| format!("cli-gpt/{}", env!("CARGO_PKG_VERSION"))),
+            aliases: settings.aliases,
+            alias_sigil: settings
+                .alias_sigil
+                .unwrap_or_else(|| DEFAULT_ALIAS_SIGIL.to_string()),
+            theme: settings.theme.unwrap_or_default(),
+        }
+    }
+
+    // where each setting comes from, the api key masked but its last 4 chars
+    pub fn describe(&self) -> String {
+        let api_key = match &self.api_key {
+            Some(key) => format!("{} ({})", mask(&key.value), key.source),
+            None => "not set".to_string(),
+        };
+        let optional = |setting: &Option<Sourced<String>>| match setting {
+            Some(setting) => format!("{} ({})", setting.value, setting.source),
+            None => "not set".to_string(),
+        };
+        let local = match paths::local_config_file() {
+            Some(path) => format!("\nlocal config: {}", path.display()),
+            None => String::new(),
+        };
+        format!(
+            "config file: {} (profile {}){local}\napi key: {api_key}\napi base: {} ({})\nmodel: {} ({})\norganization: {}\nproject: {}\nfavorite models: {} ({})",
+            paths::config_file().display(),
+            self.profile,
+            self.api_base.value,
+            self.api_base.source,
+            self.model.value,
+            self.model.source,
+            optional(&self.org_id),
+            optional(&self.project_id),
+            self.favorite_models.value.join(", "),
+            self.favorite_models.source,
+        )
+    }
+}
+
+fn env_or_config(var: &'static str, config: Option<String>) -> Option<Sourced<String>> {
+    match std::env::var(var) {
+        Ok(value) => Some(sourced(value, Source::Env(var))),
+        Err(_) => config.map(|value| sourced(value, Source::Config)),
+    }
+}
+
+pub fn mask(secret: &str) -> String {
+    let chars: Vec<char> = secret.chars().collect();
+    // a key that short is shown not at all
+    let shown = if chars.len() > 8 { 4 } else { 0 };
+    let hidden = "*".repeat(chars.len() - shown);
+    format!(
+        "{hidden}{}",
+        chars[chars.len() - shown..].iter().collect::<String>()
+    )
+}
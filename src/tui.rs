@@ -0,0 +1,70 @@
+use std::{
+    io::{stdout, Write},
+    sync::atomic::{AtomicBool, Ordering},
+};
+use termimad::crossterm::{
+    cursor, execute, queue,
+    style::Stylize,
+    terminal::{size, Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen},
+};
+use unicode_width::UnicodeWidthChar;
+
+// with --tui the conversation scrolls in a region of the alternate screen, above a status line
+// that stays on its last row. the input and the responses are drawn in the region as they
+// would be inline, the cursor moves being relative
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+pub fn enter(status: &str) {
+    let _ = execute!(stdout(), EnterAlternateScreen, cursor::MoveTo(0, 0));
+    ACTIVE.store(true, Ordering::SeqCst);
+    draw(status);
+}
+
+// set the scroll region for the current size of the terminal and draw the status line under it
+pub fn draw(status: &str) {
+    if !ACTIVE.load(Ordering::SeqCst) {
+        return;
+    }
+    let (width, height) = size().unwrap_or((80, 24));
+    let bottom = height.saturating_sub(1);
+    let (_, row) = cursor::position().unwrap_or((0, 0));
+    let mut out = stdout();
+    // setting the region homes the cursor, it's put back where it was, in the region
+    let _ = write!(out, "\x1b[1;{bottom}r");
+    let _ = queue!(
+        out,
+        cursor::MoveTo(0, bottom),
+        Clear(ClearType::CurrentLine)
+    );
+    let _ = write!(out, "{}", fit(status, width as usize).reverse());
+    let _ = queue!(out, cursor::MoveTo(0, row.min(bottom.saturating_sub(1))));
+    let _ = out.flush();
+}
+
+// back to the main screen, the conversation stays in the alternate one
+pub fn leave() {
+    leave_on(&mut stdout());
+}
+
+// the same, written to `out` (eg: stderr, when stdout may be locked by the streaming)
+pub fn leave_on(out: &mut impl Write) {
+    if ACTIVE.swap(false, Ordering::SeqCst) {
+        let _ = write!(out, "\x1b[r");
+        let _ = execute!(out, LeaveAlternateScreen);
+    }
+}
+
+// the status cut or padded to the width of the terminal
+fn fit(status: &str, width: usize) -> String {
+    let mut line = String::new();
+    let mut used = 0;
+    for c in status.chars() {
+        let w = c.width().unwrap_or(0);
+        if used + w > width {
+            break;
+        }
+        line.push(c);
+        used += w;
+    }
+    line + &" ".repeat(width - used)
+}